@@ -1,7 +1,29 @@
+use engineio_rs::packet::PacketData;
 use engineio_rs::server::*;
+use futures::StreamExt;
 
 #[tokio::main]
 async fn main() {
     simple_logger::init_with_level(log::Level::Debug).unwrap();
-    Server::<Fake, Fake>::listen().await;
+    let server = Server::<Fake, Fake>::default();
+    let mut connections = server.connections().unwrap();
+    tokio::spawn(server.clone().listen());
+    tokio::spawn(async move {
+        tokio::signal::ctrl_c().await.unwrap();
+        server.close().await;
+        std::process::exit(0);
+    });
+    // Echo every message back to its sender
+    while let Some(mut connection) = connections.next().await {
+        tokio::spawn(async move {
+            let closed = connection.closed();
+            while let Some(message) = connection.next().await {
+                let _ = match message {
+                    PacketData::Text(text) => connection.send(&text).await,
+                    PacketData::Binary(data) => connection.send_binary(&data).await,
+                };
+            }
+            log::info!("{} closed: {}", connection.sid(), closed.await);
+        });
+    }
 }
@@ -0,0 +1,134 @@
+//! Serve engine.io as a hyper `Service`
+
+use crate::server::{CORSMiddleware, Server, WSEngine};
+
+use std::convert::Infallible;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures::future;
+use hyper::server::conn::AddrStream;
+use hyper::service::Service;
+use hyper::{Body, Request, Response, StatusCode};
+
+/// Requests outside the configured path are answered with `404 Not Found`
+///
+/// The remote address of a request is read from a `SocketAddr` in its extensions,
+/// see the `Service<&AddrStream>` implementation to have it set.
+impl<W, C> Service<Request<Body>> for Server<W, C>
+where
+    W: WSEngine + Sync + Send + 'static,
+    C: CORSMiddleware + Sync + Send + 'static,
+{
+    type Response = Response<Body>;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        let server = self.clone();
+        Box::pin(async move {
+            let segments = req.uri().path().split('/').filter(|s| !s.is_empty());
+            if !segments.eq(server.option.path_segments()) {
+                let mut response = Response::new(Body::empty());
+                *response.status_mut() = StatusCode::NOT_FOUND;
+                return Ok(response);
+            }
+            Ok(server.handle_request(req).await)
+        })
+    }
+}
+
+/// `Server` makes a service per TCP connection, which knows the address of the peer
+///
+/// ```no_run
+/// # use engineio_rs::server::*;
+/// # async fn run() {
+/// let server = Server::<Fake, Fake>::default();
+/// let closed = server.closed();
+/// hyper::Server::bind(&([127, 0, 0, 1], 8080).into())
+///     .serve(server)
+///     .with_graceful_shutdown(closed)
+///     .await
+///     .unwrap();
+/// # }
+/// ```
+impl<'a, W, C> Service<&'a AddrStream> for Server<W, C>
+where
+    W: WSEngine + Sync + Send + 'static,
+    C: CORSMiddleware + Sync + Send + 'static,
+{
+    type Response = PeerService<W, C>;
+    type Error = Infallible;
+    type Future = future::Ready<Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, conn: &'a AddrStream) -> Self::Future {
+        future::ok(PeerService {
+            server: self.clone(),
+            remote_addr: conn.remote_addr(),
+        })
+    }
+}
+
+/// Serves the requests of one connection
+pub struct PeerService<W, C>
+where
+    W: WSEngine + Sync + Send + 'static,
+    C: CORSMiddleware + Sync + Send + 'static,
+{
+    server: Server<W, C>,
+    remote_addr: SocketAddr,
+}
+
+impl<W, C> Service<Request<Body>> for PeerService<W, C>
+where
+    W: WSEngine + Sync + Send + 'static,
+    C: CORSMiddleware + Sync + Send + 'static,
+{
+    type Response = Response<Body>;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Service::<Request<Body>>::poll_ready(&mut self.server, cx)
+    }
+
+    fn call(&mut self, mut req: Request<Body>) -> Self::Future {
+        req.extensions_mut().insert(self.remote_addr);
+        self.server.call(req)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::server::{Fake, Server};
+
+    use hyper::service::Service;
+    use hyper::{Body, Request, StatusCode};
+
+    #[tokio::test]
+    async fn service_matches_path() {
+        let mut server = Server::<Fake, Fake>::default();
+        let req = Request::get("/engine.io/?EIO=4&transport=polling")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(server.call(req).await.unwrap().status(), StatusCode::OK);
+
+        let req = Request::get("/socket.io/?EIO=4&transport=polling")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(
+            server.call(req).await.unwrap().status(),
+            StatusCode::NOT_FOUND
+        );
+    }
+}
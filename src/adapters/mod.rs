@@ -0,0 +1,4 @@
+//! Glue between `Server::handle_request` and HTTP frameworks
+
+pub mod hyper;
+pub mod warp;
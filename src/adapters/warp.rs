@@ -0,0 +1,256 @@
+//! Serve engine.io as a warp filter
+
+use crate::server::{error_response, CORSMiddleware, QueryParam, Server, VerifyError, WSEngine};
+
+use crate::transports::websocket::Frame;
+use crate::transports::TransportError;
+use std::net::SocketAddr;
+
+use bytes::Buf;
+use futures::{future, SinkExt, Stream, StreamExt, TryStreamExt};
+use hyper::{Body, Request};
+use log::warn;
+use tokio_tungstenite::tungstenite;
+use warp::filters::BoxedFilter;
+use warp::http::{HeaderMap, Method};
+use warp::path::FullPath;
+use warp::ws::{Message, WebSocket, Ws};
+use warp::{Filter, Rejection, Reply};
+
+impl<W, C> Server<W, C>
+where
+    W: WSEngine + Sync + Send + 'static,
+    C: CORSMiddleware + Sync + Send + 'static,
+{
+    /// Matches the configured path, e.g. `/engine.io/`
+    fn path(&self) -> BoxedFilter<()> {
+        self.option
+            .path_segments()
+            .into_iter()
+            .fold(warp::any().boxed(), |filter, segment| {
+                filter.and(warp::path(segment)).boxed()
+            })
+            .and(warp::path::end())
+            .boxed()
+    }
+
+    /// Serve engine.io as a warp filter
+    ///
+    /// The configured path is matched relative to the filters this one is combined with,
+    /// so it can be mounted under a prefix next to other routes:
+    ///
+    /// ```no_run
+    /// # use engineio_rs::server::*;
+    /// # use warp::Filter;
+    /// # async fn run() {
+    /// let server = Server::<Fake, Fake>::default();
+    /// let routes = warp::path("api")
+    ///     .and(server.filter())
+    ///     .or(warp::path("health").map(|| "ok"));
+    /// warp::serve(routes).run(([127, 0, 0, 1], 8080)).await;
+    /// # }
+    /// ```
+    ///
+    /// Filters returned by the same `Server` share its sessions.
+    pub fn filter(
+        &self,
+    ) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone + Send + Sync + 'static
+    {
+        let path = self.path();
+        let server = self.clone();
+        let server = warp::any().map(move || server.clone());
+
+        let handle_ws = path
+            .clone()
+            .and(request())
+            .and(warp::ws())
+            .and(server.clone())
+            .and_then(|req: Request<()>, ws: Ws, server: Self| async move {
+                let param = match QueryParam::parse(req.uri().query()) {
+                    Ok(param) => param,
+                    Err(e) => {
+                        warn!("Malformed query {:?}: {}", req.uri().query(), e);
+                        return Ok::<_, Rejection>(error_response(VerifyError::BadRequest));
+                    }
+                };
+                if let Err(e) = server.verify(&req, &param, true).await {
+                    warn!("Rejected upgrade {:?}: {}", param, e);
+                    return Ok(error_response(e));
+                }
+                let limit = server.option.max_http_buffer_size;
+                Ok(ws
+                    .max_message_size(limit)
+                    .max_frame_size(limit)
+                    .on_upgrade(move |socket| on_upgrade(server, param, socket))
+                    .into_response())
+            });
+        let handle_http = path
+            .and(request())
+            .and(warp::body::stream())
+            .and(server)
+            .and_then(|req: Request<()>, body, server: Self| async move {
+                let (parts, _) = req.into_parts();
+                let req = Request::from_parts(parts, into_body(body));
+                Ok::<_, Rejection>(server.handle_request(req).await)
+            });
+        handle_ws.or(handle_http)
+    }
+
+    /// Serve engine.io on the configured address until `close` is done
+    pub async fn listen(self) {
+        let addr = self.option.addr;
+        let (_, serving) =
+            warp::serve(self.filter()).bind_with_graceful_shutdown(addr, self.closed());
+        serving.await;
+    }
+}
+
+/// The request without its body, the remote address being stored in its extensions
+fn request() -> impl Filter<Extract = (Request<()>,), Error = Rejection> + Clone {
+    let query = warp::query::raw().or(warp::any().map(String::new)).unify();
+    warp::method()
+        .and(warp::path::full())
+        .and(query)
+        .and(warp::header::headers_cloned())
+        .and(warp::addr::remote())
+        .and_then(
+            |method: Method,
+             path: FullPath,
+             query: String,
+             headers: HeaderMap,
+             remote_addr: Option<SocketAddr>| async move {
+                let mut req = Request::builder()
+                    .method(method)
+                    .uri(format!("{}?{}", path.as_str(), query))
+                    .body(())
+                    .map_err(|e| {
+                        warn!("{}", e);
+                        warp::reject()
+                    })?;
+                *req.headers_mut() = headers;
+                if let Some(remote_addr) = remote_addr {
+                    req.extensions_mut().insert(remote_addr);
+                }
+                Ok::<_, Rejection>(req)
+            },
+        )
+}
+
+fn into_body<S, B>(stream: S) -> Body
+where
+    S: Stream<Item = Result<B, warp::Error>> + Send + 'static,
+    B: Buf,
+{
+    Body::wrap_stream(stream.map_ok(|mut buf| buf.to_bytes()))
+}
+
+/// Hand a WebSocket accepted by warp over to `server`
+async fn on_upgrade<W, C>(server: Server<W, C>, param: QueryParam, socket: WebSocket)
+where
+    W: WSEngine + Sync + Send + 'static,
+    C: CORSMiddleware + Sync + Send + 'static,
+{
+    let (tx, rx) = socket.split();
+    // `sink_map_err` may only map one error, the socket can see several on the way out
+    let tx = tx
+        .sink_err_into::<TransportError>()
+        .with(|frame| future::ready(from_frame(frame)));
+    let rx = rx.map_ok(to_frame).map_err(TransportError::from);
+    server
+        .on_ws_connected(param, Box::pin(tx), Box::pin(rx))
+        .await;
+}
+
+impl From<warp::Error> for TransportError {
+    fn from(e: warp::Error) -> Self {
+        let too_large = match std::error::Error::source(&e)
+            .and_then(|source| source.downcast_ref::<tungstenite::Error>())
+        {
+            Some(source) => matches!(source, tungstenite::Error::Capacity(_)),
+            // warp 0.2 gives no source, the tungstenite error then only shows through its message
+            None => e.to_string().starts_with("Space limit exceeded"),
+        };
+        if too_large {
+            return Self::MessageTooLarge;
+        }
+        Self::WebSocketError(Box::new(e))
+    }
+}
+
+fn to_frame(message: Message) -> Frame {
+    if let Ok(s) = message.to_str() {
+        Frame::Text(s.to_string())
+    } else if message.is_binary() {
+        Frame::Binary(message.into_bytes())
+    } else if message.is_ping() {
+        Frame::Ping(message.into_bytes())
+    } else if message.is_pong() {
+        Frame::Pong(message.into_bytes())
+    } else {
+        Frame::Close(None)
+    }
+}
+
+fn from_frame(frame: Frame) -> Result<Message, TransportError> {
+    match frame {
+        Frame::Text(s) => Ok(Message::text(s)),
+        Frame::Binary(b) => Ok(Message::binary(b)),
+        Frame::Ping(b) => Ok(Message::ping(b)),
+        Frame::Pong(_) => Err(TransportError::WebSocketError(
+            "warp cannot send pong frames".into(),
+        )),
+        Frame::Close(Some(frame)) => Ok(Message::close_with(frame.code, frame.reason)),
+        Frame::Close(None) => Ok(Message::close()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::server::{Fake, Server, ServerOption};
+    use crate::socket::CloseReason;
+
+    use futures::{SinkExt, StreamExt};
+    use tokio_tungstenite::tungstenite::Message;
+    use warp::http::StatusCode;
+    use warp::Filter;
+
+    #[tokio::test]
+    async fn filter_under_prefix() {
+        let server = Server::<Fake, Fake>::default();
+        let routes = warp::path("api").and(server.filter());
+        let res = warp::test::request()
+            .path("/api/engine.io/?EIO=4&transport=polling")
+            .reply(&routes)
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(res.body().starts_with(b"0{"));
+
+        let res = warp::test::request()
+            .path("/engine.io/?EIO=4&transport=polling")
+            .reply(&routes)
+            .await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn oversized_frame_closes_session() {
+        let option = ServerOption::<Fake, Fake>::builder()
+            .max_http_buffer_size(16)
+            .build()
+            .unwrap();
+        let server = Server::new(option);
+        let mut connections = server.connections().unwrap();
+        // `warp::test::ws` drops the query, so the filter is served for a real client
+        let (addr, serving) = warp::serve(server.filter()).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(serving);
+        let uri = format!("ws://{}/engine.io/?EIO=4&transport=websocket", addr);
+        let (mut client, _) = tokio_tungstenite::connect_async(uri).await.unwrap();
+        let open = client.next().await.unwrap().unwrap();
+        assert!(open.to_text().unwrap().starts_with("0{"));
+        let connection = connections.recv().await.unwrap();
+
+        let message = Message::Text(format!("4{}", "a".repeat(32)));
+        client.send(message).await.unwrap();
+        assert_eq!(connection.closed().await, CloseReason::MessageTooLarge);
+    }
+}
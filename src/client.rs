@@ -1,2 +1,60 @@
-#[derive(Debug, Clone)]
-pub struct Client {}
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+
+use crate::packet::{Packet, ProtocolVersion};
+use crate::socket::Message;
+use crate::transports::polling;
+
+use futures::future::AbortHandle;
+use tokio::sync::mpsc::Sender;
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+
+/// Server-side state of a session, looked up by sid
+#[derive(Debug)]
+pub struct Client {
+    /// Commands to its `Socket`
+    pub tx: Sender<Message>,
+    /// Packets to the client, flushed by polling requests
+    pub buffer: Arc<polling::Buffer>,
+    /// Packets of polling requests, received by the `Polling` transport
+    pub incoming: Sender<Packet>,
+    /// Held by the polling request in flight, at most one at a time
+    pub polling: Mutex<()>,
+    /// Set once an upgrade is under way or done
+    pub upgrading: AtomicBool,
+    /// Name of the transport the session is on
+    pub transport: std::sync::Mutex<&'static str>,
+    pub protocol: ProtocolVersion,
+    /// Polling payloads may use the binary XHR format, `false` if `b64` was given
+    pub supports_binary: bool,
+    /// Task running the `Socket`, taken by whoever waits for it to finish
+    pub task: Mutex<Option<JoinHandle<()>>>,
+    /// Stops the `Socket` if it does not finish on its own, see `Server::close`
+    pub abort: AbortHandle,
+}
+
+impl Client {
+    pub fn new(
+        tx: Sender<Message>,
+        buffer: Arc<polling::Buffer>,
+        incoming: Sender<Packet>,
+        transport: &'static str,
+        protocol: ProtocolVersion,
+        supports_binary: bool,
+        (task, abort): (JoinHandle<()>, AbortHandle),
+    ) -> Self {
+        Self {
+            tx,
+            buffer,
+            incoming,
+            polling: Mutex::new(()),
+            upgrading: AtomicBool::new(false),
+            transport: std::sync::Mutex::new(transport),
+            protocol,
+            supports_binary,
+            task: Mutex::new(Some(task)),
+            abort,
+        }
+    }
+}
@@ -0,0 +1,135 @@
+use crate::packet::{Packet, PacketData};
+use crate::send_buffer::SendBuffer;
+use crate::socket::{CloseReason, Message, ReadyState, SID};
+
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use futures::channel::oneshot;
+use futures::future::{FutureExt, Shared};
+use futures::Stream;
+use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::sync::watch;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SendError {
+    Closed,
+    /// The send buffer is full and its policy is `BufferPolicy::Disconnect`
+    Full,
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Closed => write!(f, "connection is closed"),
+            Self::Full => write!(f, "send buffer is full"),
+        }
+    }
+}
+
+impl std::error::Error for SendError {}
+
+/// A client session, as seen by the application
+///
+/// The connection is a stream of the messages the client sends,
+/// which ends once the session is closed.
+#[derive(Debug)]
+pub struct Connection {
+    sid: SID,
+    /// Commands to the `Socket` of this session
+    tx: Sender<Message>,
+    buffer: Arc<SendBuffer>,
+    state: watch::Receiver<ReadyState>,
+    messages: Receiver<PacketData>,
+    closed: Shared<oneshot::Receiver<CloseReason>>,
+}
+
+impl Connection {
+    pub(crate) fn new(
+        sid: SID,
+        tx: Sender<Message>,
+        buffer: Arc<SendBuffer>,
+        state: watch::Receiver<ReadyState>,
+        messages: Receiver<PacketData>,
+        closed: oneshot::Receiver<CloseReason>,
+    ) -> Self {
+        Self {
+            sid,
+            tx,
+            buffer,
+            state,
+            messages,
+            closed: closed.shared(),
+        }
+    }
+
+    pub fn sid(&self) -> &SID {
+        &self.sid
+    }
+
+    pub fn ready_state(&self) -> ReadyState {
+        *self.state.borrow()
+    }
+
+    /// Send a text message to the client
+    ///
+    /// Messages wait in the send buffer of the socket until the transport takes them,
+    /// what happens once it is full depends on its `BufferPolicy`.
+    pub async fn send(&self, message: &str) -> Result<(), SendError> {
+        self.send_packet(Packet::message(message)).await
+    }
+
+    /// Send a binary message to the client
+    pub async fn send_binary(&self, data: &[u8]) -> Result<(), SendError> {
+        self.send_packet(Packet::binary(data)).await
+    }
+
+    async fn send_packet(&self, packet: Packet) -> Result<(), SendError> {
+        let result = self.buffer.push(packet).await;
+        if result == Err(SendError::Full) {
+            let _ = self
+                .tx
+                .clone()
+                .send(Message::Disconnect(CloseReason::SendBufferFull))
+                .await;
+        }
+        result
+    }
+
+    /// Resolves once the transport has taken every message sent so far
+    pub fn drained(&self) -> impl Future<Output = ()> + Send + 'static {
+        let buffer = self.buffer.clone();
+        async move { buffer.drained().await }
+    }
+
+    /// Close the session, `closed` then resolves with `CloseReason::ForcedClose`
+    ///
+    /// Messages sent before are delivered first.
+    pub async fn close(&self) {
+        let _ = self
+            .tx
+            .clone()
+            .send(Message::Disconnect(CloseReason::ForcedClose))
+            .await;
+    }
+
+    /// Resolves once the session is closed
+    ///
+    /// The future does not borrow the connection, so it can be awaited alongside its messages.
+    pub fn closed(&self) -> impl Future<Output = CloseReason> + Send + 'static {
+        self.closed
+            .clone()
+            .map(|reason| reason.unwrap_or(CloseReason::TransportClose))
+    }
+}
+
+impl Stream for Connection {
+    type Item = PacketData;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<PacketData>> {
+        self.messages.poll_recv(cx)
+    }
+}
@@ -0,0 +1,214 @@
+use std::time::Duration;
+
+use hyper::header::{
+    HeaderMap, HeaderName, HeaderValue, ACCESS_CONTROL_ALLOW_CREDENTIALS,
+    ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN,
+    ACCESS_CONTROL_MAX_AGE, ACCESS_CONTROL_REQUEST_HEADERS, ORIGIN, VARY,
+};
+use hyper::{Body, Response, StatusCode};
+
+/// Cross-origin access to the polling transport
+///
+/// Nothing is allowed by default, which keeps polling same-origin.
+pub trait CORSMiddleware {
+    /// `Access-Control-Allow-*` headers to add to the response to a request with `request` headers
+    fn response_headers(&self, _request: &HeaderMap) -> HeaderMap {
+        HeaderMap::new()
+    }
+
+    /// Answer a preflight `OPTIONS` request
+    fn preflight(&self, request: &HeaderMap) -> Response<Body> {
+        let mut response = Response::new(Body::empty());
+        *response.status_mut() = StatusCode::NO_CONTENT;
+        response
+            .headers_mut()
+            .extend(self.response_headers(request));
+        response
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Origins {
+    Any,
+    List(Vec<String>),
+}
+
+/// `CORSMiddleware` allowing either every origin or a list of them
+///
+/// ```
+/// # use engineio_rs::cors::Cors;
+/// let cors = Cors::origins(vec!["https://app.example.com"]).credentials(true);
+/// ```
+#[derive(Debug, Clone)]
+pub struct Cors {
+    origins: Origins,
+    credentials: bool,
+    /// Request headers allowed by preflight, those asked for if `None`
+    allow_headers: Option<Vec<HeaderName>>,
+    max_age: Option<Duration>,
+}
+
+impl Cors {
+    fn new(origins: Origins) -> Self {
+        Self {
+            origins,
+            credentials: false,
+            allow_headers: None,
+            max_age: None,
+        }
+    }
+
+    /// Allow every origin
+    pub fn any() -> Self {
+        Self::new(Origins::Any)
+    }
+
+    /// Allow the given origins only, e.g. `https://example.com`
+    pub fn origins<I, S>(origins: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Self::new(Origins::List(origins.into_iter().map(Into::into).collect()))
+    }
+
+    /// Let browsers send cookies along
+    ///
+    /// Browsers refuse `*` for such requests, so the origin is echoed back instead.
+    pub fn credentials(mut self, credentials: bool) -> Self {
+        self.credentials = credentials;
+        self
+    }
+
+    /// Request headers allowed by preflight, those the browser asks for by default
+    pub fn allow_headers<I>(mut self, headers: I) -> Self
+    where
+        I: IntoIterator<Item = HeaderName>,
+    {
+        self.allow_headers = Some(headers.into_iter().collect());
+        self
+    }
+
+    /// How long browsers may cache the answer to a preflight
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    fn allows(&self, origin: &HeaderValue) -> bool {
+        match &self.origins {
+            Origins::Any => true,
+            Origins::List(origins) => origins.iter().any(|o| o.as_bytes() == origin.as_bytes()),
+        }
+    }
+}
+
+impl CORSMiddleware for Cors {
+    fn response_headers(&self, request: &HeaderMap) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        let origin = match request.get(ORIGIN) {
+            Some(origin) if self.allows(origin) => origin,
+            _ => return headers,
+        };
+        match self.origins {
+            Origins::Any if !self.credentials => {
+                headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, HeaderValue::from_static("*"));
+            }
+            _ => {
+                headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, origin.clone());
+                headers.insert(VARY, HeaderValue::from_static("Origin"));
+            }
+        }
+        if self.credentials {
+            headers.insert(
+                ACCESS_CONTROL_ALLOW_CREDENTIALS,
+                HeaderValue::from_static("true"),
+            );
+        }
+        headers
+    }
+
+    fn preflight(&self, request: &HeaderMap) -> Response<Body> {
+        let mut response = Response::new(Body::empty());
+        *response.status_mut() = StatusCode::NO_CONTENT;
+        let mut headers = self.response_headers(request);
+        if headers.contains_key(ACCESS_CONTROL_ALLOW_ORIGIN) {
+            headers.insert(
+                ACCESS_CONTROL_ALLOW_METHODS,
+                HeaderValue::from_static("GET, POST, OPTIONS"),
+            );
+            let allow_headers = match &self.allow_headers {
+                Some(names) => {
+                    let names: Vec<&str> = names.iter().map(|n| n.as_str()).collect();
+                    HeaderValue::from_str(&names.join(", ")).ok()
+                }
+                None => request.get(ACCESS_CONTROL_REQUEST_HEADERS).cloned(),
+            };
+            if let Some(allow_headers) = allow_headers {
+                headers.insert(ACCESS_CONTROL_ALLOW_HEADERS, allow_headers);
+            }
+            if let Some(max_age) = self.max_age {
+                headers.insert(ACCESS_CONTROL_MAX_AGE, max_age.as_secs().into());
+            }
+        }
+        response.headers_mut().extend(headers);
+        response
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn request(origin: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(ORIGIN, HeaderValue::from_static(origin));
+        headers
+    }
+
+    #[test]
+    fn origin_allowlist() {
+        let cors = Cors::origins(vec!["https://a.example"]);
+        let headers = cors.response_headers(&request("https://a.example"));
+        assert_eq!(headers[ACCESS_CONTROL_ALLOW_ORIGIN], "https://a.example");
+        assert_eq!(headers[VARY], "Origin");
+        assert!(cors
+            .response_headers(&request("https://b.example"))
+            .is_empty());
+        assert!(cors.response_headers(&HeaderMap::new()).is_empty());
+    }
+
+    #[test]
+    fn wildcard_and_credentials() {
+        let headers = Cors::any().response_headers(&request("https://a.example"));
+        assert_eq!(headers[ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+        assert!(!headers.contains_key(ACCESS_CONTROL_ALLOW_CREDENTIALS));
+
+        let headers = Cors::any()
+            .credentials(true)
+            .response_headers(&request("https://a.example"));
+        assert_eq!(headers[ACCESS_CONTROL_ALLOW_ORIGIN], "https://a.example");
+        assert_eq!(headers[ACCESS_CONTROL_ALLOW_CREDENTIALS], "true");
+    }
+
+    #[test]
+    fn preflight() {
+        let cors = Cors::origins(vec!["https://a.example"]).max_age(Duration::from_secs(600));
+        let mut headers = request("https://a.example");
+        headers.insert(
+            ACCESS_CONTROL_REQUEST_HEADERS,
+            HeaderValue::from_static("content-type"),
+        );
+        let response = cors.preflight(&headers);
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let headers = response.headers();
+        assert_eq!(headers[ACCESS_CONTROL_ALLOW_METHODS], "GET, POST, OPTIONS");
+        assert_eq!(headers[ACCESS_CONTROL_ALLOW_HEADERS], "content-type");
+        assert_eq!(headers[ACCESS_CONTROL_MAX_AGE], "600");
+
+        let response = cors.preflight(&request("https://b.example"));
+        assert!(!response
+            .headers()
+            .contains_key(ACCESS_CONTROL_ALLOW_METHODS));
+    }
+}
@@ -1,7 +1,9 @@
-#![feature(box_syntax, async_closure)]
+pub mod adapters;
 pub mod client;
+pub mod connection;
+pub mod cors;
 pub mod packet;
+pub mod send_buffer;
 pub mod server;
 pub mod socket;
 pub mod transports;
-pub mod util;
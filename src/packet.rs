@@ -1,29 +1,68 @@
 /// Reference https://github.com/socketio/engine.io-protocol
 use crate::socket::SID;
 
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone)]
-pub enum PollingType {
-    JSONP,
-    XHR,
+/// Separates packets of a payload in protocol v4
+const RECORD_SEPARATOR: char = '\x1e';
+
+/// Revision of the engine.io protocol, negotiated by the `EIO` query parameter
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ProtocolVersion {
+    #[default]
+    V3,
+    V4,
 }
 
-#[derive(Debug, Clone)]
-pub enum Transport {
-    WebSocket,
-    Polling(PollingType),
+impl FromStr for ProtocolVersion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "3" => Ok(Self::V3),
+            "4" => Ok(Self::V4),
+            _ => Err(format!("unsupported protocol version {:?}", s)),
+        }
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum DecodeError {
-    Err(String),
+    EmptyPacket,
+    EmptyPayload,
+    UnknownPacketType(char),
+    InvalidBase64(base64::DecodeError),
+    /// A length prefix is not a number or does not match the data
+    InvalidLength,
+    InvalidUtf8,
+    /// The payload framing is broken, e.g. a length prefix without a separator
+    InvalidPayload,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::EmptyPacket => write!(f, "empty packet"),
+            Self::EmptyPayload => write!(f, "empty payload"),
+            Self::UnknownPacketType(c) => write!(f, "unknown packet type {:?}", c),
+            Self::InvalidBase64(e) => write!(f, "invalid base64: {}", e),
+            Self::InvalidLength => write!(f, "invalid length"),
+            Self::InvalidUtf8 => write!(f, "invalid utf-8"),
+            Self::InvalidPayload => write!(f, "invalid payload"),
+        }
+    }
 }
 
+impl std::error::Error for DecodeError {}
+
 type PacketDecodeResult = Result<Packet, DecodeError>;
 type PayloadDecodeResult = Result<Payload, DecodeError>;
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub enum PacketType {
     Open = 0,
     Close,
@@ -34,97 +73,229 @@ pub enum PacketType {
     Noop,
 }
 
-impl From<String> for PacketType {
-    fn from(s: String) -> Self {
-        match s.as_str() {
-            "0" => Self::Open,
-            "1" => Self::Close,
-            "2" => Self::Ping,
-            "3" => Self::Pong,
-            "4" => Self::Message,
-            "5" => Self::Upgrade,
-            _ => Self::Noop,
+impl TryFrom<char> for PacketType {
+    type Error = DecodeError;
+
+    fn try_from(c: char) -> Result<Self, Self::Error> {
+        match c {
+            '0' => Ok(Self::Open),
+            '1' => Ok(Self::Close),
+            '2' => Ok(Self::Ping),
+            '3' => Ok(Self::Pong),
+            '4' => Ok(Self::Message),
+            '5' => Ok(Self::Upgrade),
+            '6' => Ok(Self::Noop),
+            _ => Err(DecodeError::UnknownPacketType(c)),
+        }
+    }
+}
+
+impl TryFrom<u8> for PacketType {
+    type Error = DecodeError;
+
+    fn try_from(b: u8) -> Result<Self, Self::Error> {
+        match b {
+            0..=6 => Self::try_from((b'0' + b) as char),
+            _ => Err(DecodeError::UnknownPacketType(b as char)),
         }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Body of the open packet
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
-struct WelcomeMessage {
-    sid: String,
-    upgrades: Vec<String>,
-    ping_interval: u32,
-    ping_timeout: u32,
+pub struct WelcomeMessage {
+    pub sid: SID,
+    pub upgrades: Vec<String>,
+    pub ping_interval: u64, // milliseconds
+    pub ping_timeout: u64,  // milliseconds
+    /// Largest payload the server accepts, in bytes (v4 only)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_payload: Option<usize>,
+}
+
+/// Body of a packet, sent as a text or binary frame
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum PacketData {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+impl PacketData {
+    fn empty() -> Self {
+        Self::Text(String::new())
+    }
+
+    pub fn is_binary(&self) -> bool {
+        match self {
+            Self::Text(_) => false,
+            Self::Binary(_) => true,
+        }
+    }
+
+    /// Size of the data in bytes
+    pub fn len(&self) -> usize {
+        match self {
+            Self::Text(s) => s.len(),
+            Self::Binary(b) => b.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Packet {
     pub typ: PacketType,
-    pub message: String,
+    pub data: PacketData,
 }
 
 impl Packet {
-    pub fn open(sid: SID) -> Self {
-        let welcome = WelcomeMessage {
-            sid,
-            upgrades: Vec::new(),
-            ping_interval: 25000,
-            ping_timeout: 5000,
-        };
-        let message = serde_json::to_string(&welcome).unwrap();
+    pub fn open(welcome: &WelcomeMessage) -> Self {
+        let message = serde_json::to_string(welcome).unwrap();
         Self {
             typ: PacketType::Open,
-            message,
+            data: PacketData::Text(message),
         }
     }
 
     pub fn close() -> Self {
-        unimplemented!()
+        Self {
+            typ: PacketType::Close,
+            data: PacketData::empty(),
+        }
     }
 
     pub fn ping() -> Self {
         Self {
             typ: PacketType::Ping,
-            message: String::new(),
+            data: PacketData::empty(),
         }
     }
 
     pub fn pong() -> Self {
         Self {
             typ: PacketType::Pong,
-            message: String::new(),
+            data: PacketData::empty(),
         }
     }
 
     pub fn message(message: &str) -> Self {
         Self {
             typ: PacketType::Message,
-            message: message.to_string(),
+            data: PacketData::Text(message.to_string()),
+        }
+    }
+
+    pub fn binary(data: &[u8]) -> Self {
+        Self {
+            typ: PacketType::Message,
+            data: PacketData::Binary(data.to_vec()),
+        }
+    }
+
+    /// `3probe`, the answer to a `2probe` sent over a transport being upgraded to
+    pub fn probe() -> Self {
+        Self {
+            typ: PacketType::Pong,
+            data: PacketData::Text("probe".to_string()),
         }
     }
 
+    /// Whether this is the `2probe` a client starts an upgrade with
+    pub fn is_probe(&self) -> bool {
+        self.typ == PacketType::Ping && self.data == PacketData::Text("probe".to_string())
+    }
+
     pub fn upgrade() -> Self {
-        unimplemented!()
+        Self {
+            typ: PacketType::Upgrade,
+            data: PacketData::empty(),
+        }
     }
 
     pub fn noop() -> Self {
         Self {
             typ: PacketType::Noop,
-            message: String::new(),
+            data: PacketData::empty(),
+        }
+    }
+
+    pub fn is_binary(&self) -> bool {
+        self.data.is_binary()
+    }
+
+    /// Encode as a string, binary data is base64 encoded with a `b` prefix
+    pub fn encode(&self, protocol: ProtocolVersion) -> String {
+        match (&self.data, protocol) {
+            (PacketData::Text(s), _) => (self.typ as i32).to_string() + s,
+            (PacketData::Binary(b), ProtocolVersion::V3) => {
+                format!("b{}{}", self.typ as i32, base64::encode(b))
+            }
+            (PacketData::Binary(b), ProtocolVersion::V4) => format!("b{}", base64::encode(b)),
         }
     }
 
-    pub fn encode(&self) -> String {
-        (self.typ as i32).to_string() + &self.message
+    /// Encode as a binary frame
+    ///
+    /// v3 prefixes the data with the packet type as a byte, v4 sends the data as is.
+    pub fn encode_binary(&self, protocol: ProtocolVersion) -> Vec<u8> {
+        let data = match &self.data {
+            PacketData::Text(s) => s.as_bytes(),
+            PacketData::Binary(b) => b.as_slice(),
+        };
+        match protocol {
+            ProtocolVersion::V3 => {
+                let mut ret = Vec::with_capacity(data.len() + 1);
+                ret.push(self.typ as u8);
+                ret.extend_from_slice(data);
+                ret
+            }
+            ProtocolVersion::V4 => data.to_vec(),
+        }
     }
 
-    pub fn decode(msg: &str) -> PacketDecodeResult {
-        let (event_str, msg) = msg.split_at(1);
-        let typ = PacketType::from(event_str.to_string());
-        // TODO Return err if invalid
+    pub fn decode(msg: &str, protocol: ProtocolVersion) -> PacketDecodeResult {
+        let mut chars = msg.chars();
+        let typ = match chars.next() {
+            Some('b') => return Self::decode_base64(chars.as_str(), protocol),
+            Some(c) => PacketType::try_from(c)?,
+            None => return Err(DecodeError::EmptyPacket),
+        };
         Ok(Self {
             typ,
-            message: msg.to_string(),
+            data: PacketData::Text(chars.as_str().to_string()),
+        })
+    }
+
+    pub fn decode_binary(data: &[u8], protocol: ProtocolVersion) -> PacketDecodeResult {
+        match protocol {
+            ProtocolVersion::V3 => {
+                let (typ, data) = data.split_first().ok_or(DecodeError::EmptyPacket)?;
+                Ok(Self {
+                    typ: PacketType::try_from(*typ)?,
+                    data: PacketData::Binary(data.to_vec()),
+                })
+            }
+            ProtocolVersion::V4 => Ok(Self::binary(data)),
+        }
+    }
+
+    fn decode_base64(msg: &str, protocol: ProtocolVersion) -> PacketDecodeResult {
+        let (typ, msg) = match protocol {
+            ProtocolVersion::V3 => {
+                let mut chars = msg.chars();
+                let typ = chars.next().ok_or(DecodeError::EmptyPacket)?;
+                (PacketType::try_from(typ)?, chars.as_str())
+            }
+            ProtocolVersion::V4 => (PacketType::Message, msg),
+        };
+        let data = base64::decode(msg).map_err(DecodeError::InvalidBase64)?;
+        Ok(Self {
+            typ,
+            data: PacketData::Binary(data),
         })
     }
 }
@@ -135,18 +306,160 @@ pub struct Payload {
 }
 
 impl Payload {
-    pub fn encode(&self) -> String {
-        let s = self
-            .packets
+    pub fn encode(&self, protocol: ProtocolVersion) -> String {
+        match protocol {
+            ProtocolVersion::V3 => self.encode_v3(),
+            ProtocolVersion::V4 => self.encode_v4(),
+        }
+    }
+
+    pub fn decode(s: &str, protocol: ProtocolVersion) -> PayloadDecodeResult {
+        if s.is_empty() {
+            return Err(DecodeError::EmptyPayload);
+        }
+        match protocol {
+            ProtocolVersion::V3 => Self::decode_v3(s),
+            ProtocolVersion::V4 => Self::decode_v4(s),
+        }
+    }
+
+    pub fn has_binary(&self) -> bool {
+        self.packets.iter().any(Packet::is_binary)
+    }
+
+    /// Encode packets in the v3 binary XHR format
+    ///
+    /// Each packet is preceded by `0` (string) or `1` (binary),
+    /// the length as a sequence of decimal digit bytes and a `0xff` separator.
+    pub fn encode_binary(&self) -> Vec<u8> {
+        let mut ret = Vec::new();
+        for p in self.packets.iter() {
+            let (kind, data) = if p.is_binary() {
+                (1, p.encode_binary(ProtocolVersion::V3))
+            } else {
+                (0, p.encode(ProtocolVersion::V3).into_bytes())
+            };
+            ret.push(kind);
+            ret.extend(data.len().to_string().bytes().map(|d| d - b'0'));
+            ret.push(0xff);
+            ret.extend(data);
+        }
+        ret
+    }
+
+    pub fn decode_binary(data: &[u8]) -> PayloadDecodeResult {
+        if data.is_empty() {
+            return Err(DecodeError::EmptyPayload);
+        }
+        let mut packets = Vec::new();
+        let mut rest = data;
+        while !rest.is_empty() {
+            let is_string = match rest[0] {
+                0 => true,
+                1 => false,
+                _ => return Err(DecodeError::InvalidPayload),
+            };
+            let sep = rest
+                .iter()
+                .position(|&b| b == 0xff)
+                .ok_or(DecodeError::InvalidPayload)?;
+            let mut len: usize = 0;
+            for &d in rest[1..sep].iter() {
+                if d > 9 {
+                    return Err(DecodeError::InvalidLength);
+                }
+                len = len
+                    .checked_mul(10)
+                    .and_then(|l| l.checked_add(d as usize))
+                    .ok_or(DecodeError::InvalidLength)?;
+            }
+            let body = &rest[sep + 1..];
+            if sep == 1 || len > body.len() {
+                return Err(DecodeError::InvalidLength);
+            }
+            let (packet, next) = body.split_at(len);
+            packets.push(if is_string {
+                let s = std::str::from_utf8(packet).map_err(|_| DecodeError::InvalidUtf8)?;
+                Packet::decode(s, ProtocolVersion::V3)?
+            } else {
+                Packet::decode_binary(packet, ProtocolVersion::V3)?
+            });
+            rest = next;
+        }
+        Ok(Self { packets })
+    }
+
+    /// Encode packets as `<length>:<packet><length>:<packet>...`
+    fn encode_v3(&self) -> String {
+        self.packets
             .iter()
-            .map(|p| p.encode())
-            .fold(String::new(), |x, y| x + &y);
-        format!("{}:{}", s.len(), s)
+            .map(|p| {
+                let s = p.encode(ProtocolVersion::V3);
+                format!("{}:{}", utf16_len(&s), s)
+            })
+            .collect()
+    }
+
+    /// Encode packets as `<packet>\x1e<packet>...`
+    fn encode_v4(&self) -> String {
+        self.packets
+            .iter()
+            .map(|p| p.encode(ProtocolVersion::V4))
+            .collect::<Vec<_>>()
+            .join(&RECORD_SEPARATOR.to_string())
+    }
+
+    fn decode_v3(s: &str) -> PayloadDecodeResult {
+        let mut packets = Vec::new();
+        let mut rest = s;
+        while !rest.is_empty() {
+            let colon = rest.find(':').ok_or(DecodeError::InvalidPayload)?;
+            let len = &rest[..colon];
+            // `parse` would also take a leading `+`
+            if len.is_empty() || !len.bytes().all(|b| b.is_ascii_digit()) {
+                return Err(DecodeError::InvalidLength);
+            }
+            let len = len
+                .parse::<usize>()
+                .map_err(|_| DecodeError::InvalidLength)?;
+            let body = &rest[colon + 1..];
+            let end = utf16_offset(body, len).ok_or(DecodeError::InvalidLength)?;
+            if end > 0 {
+                packets.push(Packet::decode(&body[..end], ProtocolVersion::V3)?);
+            }
+            rest = &body[end..];
+        }
+        Ok(Self { packets })
     }
 
-    pub fn decode(s: &str) -> PayloadDecodeResult {
-        // TODO Return err if invalid
-        unimplemented!()
+    fn decode_v4(s: &str) -> PayloadDecodeResult {
+        let packets = s
+            .split(RECORD_SEPARATOR)
+            .map(|p| Packet::decode(p, ProtocolVersion::V4))
+            .collect::<Result<_, _>>()?;
+        Ok(Self { packets })
+    }
+}
+
+/// Length of `s` in UTF-16 code units, which is how the JS reference counts it
+fn utf16_len(s: &str) -> usize {
+    s.chars().map(char::len_utf16).sum()
+}
+
+/// Byte offset in `s` after `len` UTF-16 code units,
+/// or `None` if `s` is shorter or `len` ends inside a character
+fn utf16_offset(s: &str, len: usize) -> Option<usize> {
+    let mut units = 0;
+    for (i, c) in s.char_indices() {
+        if units == len {
+            return Some(i);
+        }
+        units += c.len_utf16();
+    }
+    if units == len {
+        Some(s.len())
+    } else {
+        None
     }
 }
 
@@ -163,4 +476,148 @@ impl From<Payload> for Vec<Packet> {
 }
 
 #[cfg(test)]
-mod test {}
+mod test {
+    use super::*;
+
+    fn messages(payload: &Payload) -> Vec<String> {
+        payload
+            .packets
+            .iter()
+            .map(|p| p.encode(ProtocolVersion::V3))
+            .collect()
+    }
+
+    #[test]
+    fn payload_roundtrip() {
+        let payload = Payload::from(vec![Packet::message("hello"), Packet::ping()]);
+        let encoded = payload.encode(ProtocolVersion::V3);
+        assert_eq!(encoded, "6:4hello1:2");
+        let decoded = Payload::decode(&encoded, ProtocolVersion::V3).unwrap();
+        assert_eq!(messages(&decoded), vec!["4hello", "2"]);
+    }
+
+    #[test]
+    fn payload_counts_utf16_units() {
+        // "€" is one UTF-16 unit, "😀" is two
+        let decoded = Payload::decode("2:4€3:4😀", ProtocolVersion::V3).unwrap();
+        assert_eq!(messages(&decoded), vec!["4€", "4😀"]);
+        assert_eq!(decoded.encode(ProtocolVersion::V3), "2:4€3:4😀");
+    }
+
+    #[test]
+    fn payload_rejects_malformed_input() {
+        let decode = |s| Payload::decode(s, ProtocolVersion::V3).unwrap_err();
+        assert_eq!(decode(""), DecodeError::EmptyPayload);
+        assert_eq!(decode("4hello"), DecodeError::InvalidPayload);
+        assert_eq!(decode("x:4hello"), DecodeError::InvalidLength);
+        assert_eq!(decode("+6:4hello"), DecodeError::InvalidLength);
+        assert_eq!(decode(":4hello"), DecodeError::InvalidLength);
+        assert_eq!(decode("10:4hello"), DecodeError::InvalidLength);
+        assert_eq!(decode("6:4hello3"), DecodeError::InvalidPayload);
+        assert_eq!(decode("6:9hello"), DecodeError::UnknownPacketType('9'));
+        // Length ends in the middle of a surrogate pair
+        assert_eq!(decode("2:4😀"), DecodeError::InvalidLength);
+    }
+
+    #[test]
+    fn packet_rejects_malformed_input() {
+        let decode = |s| Packet::decode(s, ProtocolVersion::V3).unwrap_err();
+        assert_eq!(decode(""), DecodeError::EmptyPacket);
+        assert_eq!(decode("7"), DecodeError::UnknownPacketType('7'));
+        assert_eq!(decode("€4"), DecodeError::UnknownPacketType('€'));
+        assert_eq!(decode("b"), DecodeError::EmptyPacket);
+        assert!(matches!(decode("b4!!"), DecodeError::InvalidBase64(_)));
+        assert_eq!(
+            Packet::decode_binary(&[], ProtocolVersion::V3).unwrap_err(),
+            DecodeError::EmptyPacket
+        );
+        assert_eq!(
+            Packet::decode_binary(&[7], ProtocolVersion::V3).unwrap_err(),
+            DecodeError::UnknownPacketType('\u{7}')
+        );
+        assert_eq!(
+            Payload::decode("4a\x1e\x1e2", ProtocolVersion::V4).unwrap_err(),
+            DecodeError::EmptyPacket
+        );
+    }
+
+    #[test]
+    fn packet_splits_after_type_char() {
+        let packet = Packet::decode("4€", ProtocolVersion::V3).unwrap();
+        assert_eq!(packet.typ, PacketType::Message);
+        assert_eq!(packet.data, PacketData::Text("€".to_string()));
+    }
+
+    #[test]
+    fn payload_v4_roundtrip() {
+        let payload = Payload::from(vec![Packet::message("hello"), Packet::ping()]);
+        let encoded = payload.encode(ProtocolVersion::V4);
+        assert_eq!(encoded, "4hello\x1e2");
+        let decoded = Payload::decode(&encoded, ProtocolVersion::V4).unwrap();
+        assert_eq!(messages(&decoded), vec!["4hello", "2"]);
+        assert!(Payload::decode("", ProtocolVersion::V4).is_err());
+    }
+
+    #[test]
+    fn protocol_version_from_query() {
+        assert_eq!("3".parse(), Ok(ProtocolVersion::V3));
+        assert_eq!("4".parse(), Ok(ProtocolVersion::V4));
+        assert!("5".parse::<ProtocolVersion>().is_err());
+    }
+
+    #[test]
+    fn binary_packet_base64() {
+        let packet = Packet::binary(&[0, 1, 2, 255]);
+        assert_eq!(packet.encode(ProtocolVersion::V3), "b4AAEC/w==");
+        assert_eq!(packet.encode(ProtocolVersion::V4), "bAAEC/w==");
+        for &protocol in [ProtocolVersion::V3, ProtocolVersion::V4].iter() {
+            let decoded = Packet::decode(&packet.encode(protocol), protocol).unwrap();
+            assert_eq!(decoded.typ, PacketType::Message);
+            assert_eq!(decoded.data, packet.data);
+        }
+    }
+
+    #[test]
+    fn binary_packet_frame() {
+        let packet = Packet::binary(&[1, 2]);
+        assert_eq!(packet.encode_binary(ProtocolVersion::V3), vec![4, 1, 2]);
+        assert_eq!(packet.encode_binary(ProtocolVersion::V4), vec![1, 2]);
+        let decoded = Packet::decode_binary(&[4, 1, 2], ProtocolVersion::V3).unwrap();
+        assert_eq!(decoded.typ, PacketType::Message);
+        assert_eq!(decoded.data, PacketData::Binary(vec![1, 2]));
+    }
+
+    #[test]
+    fn binary_payload_roundtrip() {
+        let payload = Payload::from(vec![Packet::message("hi"), Packet::binary(&[7])]);
+        let encoded = payload.encode_binary();
+        assert_eq!(
+            encoded,
+            vec![0, 3, 0xff, b'4', b'h', b'i', 1, 2, 0xff, 4, 7]
+        );
+        let decoded = Payload::decode_binary(&encoded).unwrap();
+        assert_eq!(messages(&decoded), vec!["4hi", "b4Bw=="]);
+        assert!(Payload::decode_binary(&[0, 9, 0xff, b'4']).is_err());
+        assert!(Payload::decode_binary(&[2, 1, 0xff, b'4']).is_err());
+    }
+
+    #[test]
+    fn open_packet() {
+        let mut welcome = WelcomeMessage {
+            sid: "abc".to_string(),
+            upgrades: vec!["websocket".to_string()],
+            ping_interval: 25000,
+            ping_timeout: 20000,
+            max_payload: None,
+        };
+        assert_eq!(
+            Packet::open(&welcome).encode(ProtocolVersion::V3),
+            r#"0{"sid":"abc","upgrades":["websocket"],"pingInterval":25000,"pingTimeout":20000}"#
+        );
+        welcome.max_payload = Some(1000000);
+        assert_eq!(
+            Packet::open(&welcome).encode(ProtocolVersion::V4),
+            r#"0{"sid":"abc","upgrades":["websocket"],"pingInterval":25000,"pingTimeout":20000,"maxPayload":1000000}"#
+        );
+    }
+}
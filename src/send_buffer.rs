@@ -0,0 +1,179 @@
+use crate::connection::SendError;
+use crate::packet::Packet;
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use tokio::sync::watch;
+
+/// What sending does once the send buffer of a socket is full
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BufferPolicy {
+    /// Wait until the transport has taken enough packets
+    Wait,
+    /// Drop the oldest buffered packet to make room
+    DropOldest,
+    /// Close the session, see `CloseReason::SendBufferFull`
+    Disconnect,
+}
+
+/// Packets from the application that the transport has not taken yet
+#[derive(Debug)]
+pub struct SendBuffer {
+    packets: Mutex<VecDeque<Packet>>,
+    limit: usize,
+    policy: BufferPolicy,
+    /// Set once the socket is closed, nothing is taken from then on
+    closed: AtomicBool,
+    /// Number of buffered packets, broadcast on every change
+    len_tx: watch::Sender<usize>,
+    len_rx: watch::Receiver<usize>,
+}
+
+impl SendBuffer {
+    pub fn new(limit: usize, policy: BufferPolicy) -> Self {
+        let (len_tx, len_rx) = watch::channel(0);
+        Self {
+            packets: Mutex::new(VecDeque::new()),
+            limit,
+            policy,
+            closed: AtomicBool::new(false),
+            len_tx,
+            len_rx,
+        }
+    }
+
+    /// Buffer a packet, applying the policy if the buffer is full
+    pub async fn push(&self, packet: Packet) -> Result<(), SendError> {
+        let mut len_rx = self.len_rx.clone();
+        loop {
+            if self.closed.load(Ordering::SeqCst) {
+                return Err(SendError::Closed);
+            }
+            {
+                let mut packets = self.packets.lock().unwrap();
+                if packets.len() < self.limit {
+                    packets.push_back(packet);
+                    let len = packets.len();
+                    drop(packets);
+                    let _ = self.len_tx.broadcast(len);
+                    return Ok(());
+                }
+                match self.policy {
+                    BufferPolicy::Wait => {}
+                    BufferPolicy::DropOldest => {
+                        packets.pop_front();
+                        packets.push_back(packet);
+                        return Ok(());
+                    }
+                    BufferPolicy::Disconnect => return Err(SendError::Full),
+                }
+            }
+            len_rx.recv().await;
+        }
+    }
+
+    pub fn take_all(&self) -> Vec<Packet> {
+        let packets = self.packets.lock().unwrap().drain(..).collect();
+        let _ = self.len_tx.broadcast(0);
+        packets
+    }
+
+    /// Wait until there are packets to take
+    pub async fn pushed(&self) {
+        self.wait_for(|len| len > 0).await
+    }
+
+    /// Wait until every packet has been taken, or the socket is closed
+    pub async fn drained(&self) {
+        self.wait_for(|len| len == 0).await
+    }
+
+    /// Fail pending and later pushes
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        let len = self.packets.lock().unwrap().len();
+        let _ = self.len_tx.broadcast(len);
+    }
+
+    async fn wait_for(&self, ready: impl Fn(usize) -> bool) {
+        let mut len_rx = self.len_rx.clone();
+        loop {
+            let len = self.packets.lock().unwrap().len();
+            if ready(len) || self.closed.load(Ordering::SeqCst) {
+                return;
+            }
+            len_rx.recv().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::packet::PacketData;
+
+    use std::sync::Arc;
+    use tokio::time::{self, Duration};
+
+    fn texts(packets: Vec<Packet>) -> Vec<PacketData> {
+        packets.into_iter().map(|p| p.data).collect()
+    }
+
+    #[tokio::test]
+    async fn wait_for_room() {
+        let buffer = Arc::new(SendBuffer::new(1, BufferPolicy::Wait));
+        buffer.push(Packet::message("a")).await.unwrap();
+        let push = tokio::spawn({
+            let buffer = buffer.clone();
+            async move { buffer.push(Packet::message("b")).await }
+        });
+        time::delay_for(Duration::from_millis(10)).await;
+        assert_eq!(texts(buffer.take_all()), vec![PacketData::Text("a".into())]);
+        push.await.unwrap().unwrap();
+        assert_eq!(texts(buffer.take_all()), vec![PacketData::Text("b".into())]);
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_and_disconnect() {
+        let buffer = SendBuffer::new(2, BufferPolicy::DropOldest);
+        for m in ["a", "b", "c"].iter() {
+            buffer.push(Packet::message(m)).await.unwrap();
+        }
+        assert_eq!(
+            texts(buffer.take_all()),
+            vec![PacketData::Text("b".into()), PacketData::Text("c".into())]
+        );
+
+        let buffer = SendBuffer::new(1, BufferPolicy::Disconnect);
+        buffer.push(Packet::message("a")).await.unwrap();
+        assert_eq!(
+            buffer.push(Packet::message("b")).await,
+            Err(SendError::Full)
+        );
+    }
+
+    #[tokio::test]
+    async fn drained_and_closed() {
+        let buffer = Arc::new(SendBuffer::new(1, BufferPolicy::Wait));
+        buffer.drained().await;
+        buffer.push(Packet::message("a")).await.unwrap();
+        let drained = tokio::spawn({
+            let buffer = buffer.clone();
+            async move { buffer.drained().await }
+        });
+        buffer.pushed().await;
+        buffer.take_all();
+        drained.await.unwrap();
+
+        buffer.push(Packet::message("b")).await.unwrap();
+        let push = tokio::spawn({
+            let buffer = buffer.clone();
+            async move { buffer.push(Packet::message("c")).await }
+        });
+        time::delay_for(Duration::from_millis(10)).await;
+        buffer.close();
+        assert_eq!(push.await.unwrap(), Err(SendError::Closed));
+    }
+}
@@ -1,66 +1,624 @@
 use std::collections::HashMap;
-use std::convert::Infallible;
-use std::marker::PhantomData;
-use std::marker::Sync;
-use std::sync::mpsc::{channel, Sender};
+use std::fmt;
+use std::future::Future;
+use std::marker::{PhantomData, Sync};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use std::thread;
-use tokio::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, watch, Mutex};
+use tokio::time;
 
-use crate::packet::{Packet, Payload};
-use crate::socket::{Message, Socket, SID};
-use crate::transports::{polling, websocket};
-use crate::util;
+use crate::client::Client;
+use crate::connection::Connection;
+use crate::packet::{DecodeError, Packet, PacketType, Payload, ProtocolVersion};
+use crate::socket::{
+    self, Base64Id, CloseReason, EngineIOSocket, Message, SidGenerator, Socket, SocketOption, SID,
+};
+use crate::transports::polling_jsonp::{self, Jsonp};
+use crate::transports::websocket::{FrameSink, FrameStream};
+use crate::transports::{self, polling, websocket, DynTransport, Transport, TransportError};
 
-use futures::StreamExt;
+use bytes::{Bytes, BytesMut};
+use futures::future::{self, BoxFuture};
+use futures::{FutureExt, SinkExt, StreamExt, TryStreamExt};
+use headers::{HeaderMapExt, SecWebsocketAccept, SecWebsocketKey, Upgrade};
+use hyper::body::HttpBody;
+use hyper::header::{HeaderMap, HeaderValue, CONTENT_TYPE, SET_COOKIE, UPGRADE};
+use hyper::{Body, Method, Request, Response, StatusCode};
 use log::{debug, error, info, trace, warn};
 use serde::Deserialize;
-use warp::filters::ws::WebSocket as WSFilter;
-use warp::ws::WebSocket;
-use warp::Filter;
+use tokio_tungstenite::tungstenite::protocol::{Role, WebSocketConfig};
+use tokio_tungstenite::WebSocketStream;
 
+#[derive(Debug, Default)]
 pub struct Fake {}
 impl WSEngine for Fake {}
 impl CORSMiddleware for Fake {}
 
+/// Errors a request is answered with, as defined by the reference implementation
+#[derive(Debug, Clone, PartialEq)]
 pub enum VerifyError {
     UnknownTransport,
     UnknownSID,
     BadHandshakeMethod,
+    BadRequest,
+    Forbidden,
+    UnsupportedProtocolVersion,
+    /// Refused by the `allow_request` hook
+    Denied {
+        code: u8,
+        message: String,
+    },
 }
 
+impl VerifyError {
+    /// Code sent to the client in the error body
+    pub fn code(&self) -> u8 {
+        match self {
+            Self::UnknownTransport => 0,
+            Self::UnknownSID => 1,
+            Self::BadHandshakeMethod => 2,
+            Self::BadRequest => 3,
+            Self::Forbidden => 4,
+            Self::UnsupportedProtocolVersion => 5,
+            Self::Denied { code, .. } => *code,
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::Forbidden | Self::Denied { .. } => StatusCode::FORBIDDEN,
+            _ => StatusCode::BAD_REQUEST,
+        }
+    }
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let message = match self {
+            Self::UnknownTransport => "Transport unknown",
+            Self::UnknownSID => "Session ID unknown",
+            Self::BadHandshakeMethod => "Bad handshake method",
+            Self::BadRequest => "Bad request",
+            Self::Forbidden => "Forbidden",
+            Self::UnsupportedProtocolVersion => "Unsupported protocol version",
+            Self::Denied { message, .. } => message,
+        };
+        f.write_str(message)
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
 pub type VerifyResult = Result<(), VerifyError>;
 
-// TODO
-pub struct Cookie {}
+/// Why a verified handshake did not open a session
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum OpenError {
+    /// `Server::close` started after the request was verified
+    Closing,
+    /// The `SidGenerator` gave no valid and unused sid, which is the server's fault
+    NoSid,
+    /// `CONNECTION_CAPACITY` sessions are waiting for the application already
+    Busy,
+}
+
+impl OpenError {
+    fn response(self) -> Response<Body> {
+        match self {
+            Self::Closing => error_response(VerifyError::Forbidden),
+            Self::NoSid => {
+                let mut response = text_response("Internal Server Error".to_string());
+                *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                response
+            }
+            Self::Busy => {
+                let mut response = text_response("Service Unavailable".to_string());
+                *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+                response
+            }
+        }
+    }
+}
+
+/// What the `allow_request` hook is given of a handshake request
+#[derive(Debug, Clone)]
+pub struct HandshakeRequest {
+    pub headers: HeaderMap,
+    pub query: HashMap<String, String>,
+    /// Address of the peer, if the HTTP stack provides it
+    pub remote_addr: Option<SocketAddr>,
+    pub cookies: HashMap<String, String>,
+}
+
+impl HandshakeRequest {
+    fn new<B>(req: &Request<B>) -> Self {
+        let query = serde_urlencoded::from_str(req.uri().query().unwrap_or("")).unwrap_or_default();
+        let cookies = req
+            .headers()
+            .typed_get::<headers::Cookie>()
+            .map(|cookie| {
+                cookie
+                    .iter()
+                    .map(|(name, value)| (name.to_string(), value.to_string()))
+                    .collect()
+            })
+            .unwrap_or_default();
+        Self {
+            headers: req.headers().clone(),
+            query,
+            remote_addr: req.extensions().get::<SocketAddr>().copied(),
+            cookies,
+        }
+    }
+}
+
+/// Answer of the `allow_request` hook
+#[derive(Debug, Clone, PartialEq)]
+pub enum Authorization {
+    Allow,
+    /// Refuse the handshake, the client is sent `code` and `message`
+    Deny {
+        code: u8,
+        message: String,
+    },
+}
+
+struct AllowRequest(
+    Box<dyn Fn(HandshakeRequest) -> BoxFuture<'static, Authorization> + Send + Sync>,
+);
+
+impl fmt::Debug for AllowRequest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("AllowRequest")
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    /// Browsers only accept it along with `Secure`
+    None,
+}
+
+impl SameSite {
+    /// Value of the `SameSite` attribute
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Strict => "Strict",
+            Self::Lax => "Lax",
+            Self::None => "None",
+        }
+    }
+}
+
+/// Cookie holding the sid, set on handshake responses for sticky load balancing
+///
+/// Defaults to `io=<sid>; Path=/; HttpOnly; SameSite=Lax`.
+#[derive(Debug, Clone)]
+pub struct Cookie {
+    name: String,
+    path: Option<String>,
+    http_only: bool,
+    same_site: Option<SameSite>,
+    secure: bool,
+}
+
+impl Default for Cookie {
+    fn default() -> Self {
+        Self {
+            name: "io".to_string(),
+            path: Some("/".to_string()),
+            http_only: true,
+            same_site: Some(SameSite::Lax),
+            secure: false,
+        }
+    }
+}
+
+impl Cookie {
+    pub fn name(mut self, name: &str) -> Self {
+        self.name = name.to_string();
+        self
+    }
+
+    /// `None` leaves the path to the browser
+    pub fn path(mut self, path: Option<&str>) -> Self {
+        self.path = path.map(|p| p.to_string());
+        self
+    }
+
+    pub fn http_only(mut self, http_only: bool) -> Self {
+        self.http_only = http_only;
+        self
+    }
+
+    pub fn same_site(mut self, same_site: Option<SameSite>) -> Self {
+        self.same_site = same_site;
+        self
+    }
+
+    pub fn secure(mut self, secure: bool) -> Self {
+        self.secure = secure;
+        self
+    }
+
+    /// Whether the name and path can be sent in a `Set-Cookie` header
+    fn is_valid(&self) -> bool {
+        let token = |c: char| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c);
+        let path = |c: char| c.is_ascii() && !c.is_ascii_control() && c != ';';
+        !self.name.is_empty()
+            && self.name.chars().all(token)
+            && self.path.as_deref().is_none_or(|p| p.chars().all(path))
+    }
+
+    /// `Set-Cookie` value for the session `sid`
+    fn header(&self, sid: &SID) -> HeaderValue {
+        let mut cookie = format!("{}={}", self.name, sid);
+        if let Some(path) = &self.path {
+            cookie.push_str(&format!("; Path={}", path));
+        }
+        if self.http_only {
+            cookie.push_str("; HttpOnly");
+        }
+        if let Some(same_site) = self.same_site {
+            cookie.push_str(&format!("; SameSite={}", same_site.as_str()));
+        }
+        if self.secure {
+            cookie.push_str("; Secure");
+        }
+        // The name and path are validated by the option builder, the sid by `Server::open`
+        HeaderValue::from_str(&cookie).expect("cookie is validated")
+    }
+}
 
-// TODO
-pub trait CORSMiddleware {}
+pub use crate::cors::CORSMiddleware;
+pub use crate::send_buffer::BufferPolicy;
 
 pub trait WSEngine {}
 
+// Defaults of `ServerOptionBuilder`, see its methods
+const DEFAULT_PING_INTERVAL: Duration = Duration::from_millis(25000);
+const DEFAULT_PING_TIMEOUT: Duration = Duration::from_millis(5000);
+const DEFAULT_POLL_DURATION: Duration = Duration::from_millis(20000);
+const DEFAULT_UPGRADE_TIMEOUT: Duration = Duration::from_millis(10000);
+const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_millis(5000);
+const DEFAULT_MAX_HTTP_BUFFER_SIZE: usize = 1_000_000;
+const DEFAULT_SEND_BUFFER_SIZE: usize = 1024;
+
+/// How many sids are drawn before a handshake fails, see `SidGenerator`
+const SID_ATTEMPTS: usize = 8;
+
+/// How many sessions may wait for the application to take them, see `Server::connections`
+pub const CONNECTION_CAPACITY: usize = 64;
+
+const DEFAULT_PATH: &str = "/engine.io";
+
+const DEFAULT_ADDR: ([u8; 4], u16) = ([0, 0, 0, 0], 3030);
+
+#[derive(Debug)]
 pub struct ServerOption<W, C>
 where
     W: WSEngine,
     C: CORSMiddleware,
 {
-    ws: W,
-    ping_timeout: u32,    // milliseconds
-    ping_interval: u32,   // milliseconds,
-    upgrade_timeout: u32, // milliseconds,
-    max_http_buffer_size: u32,
+    ws: PhantomData<fn() -> W>,
+    ping_timeout: Duration,
+    ping_interval: Duration,
+    upgrade_timeout: Duration,
+    poll_duration: Duration,
+    shutdown_timeout: Duration,
+    pub(crate) max_http_buffer_size: usize,
+    jsonp: bool,
+    send_buffer_size: usize,
+    send_buffer_policy: BufferPolicy,
+    sid_generator: Arc<dyn SidGenerator>,
     cors_middleware: Option<C>,
     cookie: Option<Cookie>,
-    allow_request: bool,
+    allow_request: Option<AllowRequest>,
+    path: String,
+    pub(crate) addr: SocketAddr,
+}
+
+impl<W, C> ServerOption<W, C>
+where
+    W: WSEngine,
+    C: CORSMiddleware,
+{
+    pub fn builder() -> ServerOptionBuilder<W, C> {
+        ServerOptionBuilder::new()
+    }
+
+    fn new() -> Self {
+        Self {
+            ws: PhantomData,
+            ping_timeout: DEFAULT_PING_TIMEOUT,
+            ping_interval: DEFAULT_PING_INTERVAL,
+            upgrade_timeout: DEFAULT_UPGRADE_TIMEOUT,
+            poll_duration: DEFAULT_POLL_DURATION,
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            max_http_buffer_size: DEFAULT_MAX_HTTP_BUFFER_SIZE,
+            jsonp: true,
+            send_buffer_size: DEFAULT_SEND_BUFFER_SIZE,
+            send_buffer_policy: BufferPolicy::Wait,
+            sid_generator: Arc::new(Base64Id),
+            cors_middleware: None,
+            cookie: None,
+            allow_request: None,
+            path: DEFAULT_PATH.to_string(),
+            addr: DEFAULT_ADDR.into(),
+        }
+    }
+
+    /// Segments of `path`, e.g. `["socket", "engine.io"]` for `/socket/engine.io/`
+    pub(crate) fn path_segments(&self) -> Vec<String> {
+        self.path
+            .split('/')
+            .filter(|s| !s.is_empty())
+            .map(|s| s.to_string())
+            .collect()
+    }
+}
+
+impl<W, C> Default for ServerOption<W, C>
+where
+    W: WSEngine,
+    C: CORSMiddleware,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ServerOptionError {
+    /// The named duration is zero
+    ZeroDuration(&'static str),
+    ZeroBufferSize,
+    ZeroSendBufferSize,
+    InvalidPath(String),
+    /// The cookie name or path can not be sent in a header
+    InvalidCookie(String),
+}
+
+impl fmt::Display for ServerOptionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::ZeroDuration(name) => write!(f, "{} must be positive", name),
+            Self::ZeroBufferSize => write!(f, "max_http_buffer_size must be positive"),
+            Self::ZeroSendBufferSize => write!(f, "send_buffer_size must be positive"),
+            Self::InvalidPath(path) => write!(f, "invalid path {:?}", path),
+            Self::InvalidCookie(name) => write!(f, "invalid cookie {:?}", name),
+        }
+    }
+}
+
+impl std::error::Error for ServerOptionError {}
+
+/// Builds a `ServerOption`, starting from the defaults
+///
+/// ```no_run
+/// # use std::time::Duration;
+/// # use engineio_rs::server::*;
+/// let option = ServerOption::<Fake, Fake>::builder()
+///     .ping_interval(Duration::from_secs(10))
+///     .path("/realtime")
+///     .build()
+///     .unwrap();
+/// ```
+#[derive(Debug)]
+pub struct ServerOptionBuilder<W, C>
+where
+    W: WSEngine,
+    C: CORSMiddleware,
+{
+    option: ServerOption<W, C>,
+}
+
+impl<W, C> Default for ServerOptionBuilder<W, C>
+where
+    W: WSEngine,
+    C: CORSMiddleware,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<W, C> ServerOptionBuilder<W, C>
+where
+    W: WSEngine,
+    C: CORSMiddleware,
+{
+    pub fn new() -> Self {
+        Self {
+            option: ServerOption::new(),
+        }
+    }
+
+    /// How long to wait for a pong after a ping
+    pub fn ping_timeout(mut self, ping_timeout: Duration) -> Self {
+        self.option.ping_timeout = ping_timeout;
+        self
+    }
+
+    /// How often to ping
+    pub fn ping_interval(mut self, ping_interval: Duration) -> Self {
+        self.option.ping_interval = ping_interval;
+        self
+    }
+
+    /// How long a client may take to complete an upgrade
+    pub fn upgrade_timeout(mut self, upgrade_timeout: Duration) -> Self {
+        self.option.upgrade_timeout = upgrade_timeout;
+        self
+    }
+
+    /// How long a polling request waits for packets before it is answered with a noop
+    pub fn poll_duration(mut self, poll_duration: Duration) -> Self {
+        self.option.poll_duration = poll_duration;
+        self
+    }
+
+    /// How long a closed session waits for its close packet to be polled before it is dropped,
+    /// which also bounds `Server::close`
+    pub fn shutdown_timeout(mut self, shutdown_timeout: Duration) -> Self {
+        self.option.shutdown_timeout = shutdown_timeout;
+        self
+    }
+
+    /// Upper bound of a polling request or response and of an incoming message, in bytes
+    pub fn max_http_buffer_size(mut self, max_http_buffer_size: usize) -> Self {
+        self.option.max_http_buffer_size = max_http_buffer_size;
+        self
+    }
+
+    /// Whether polling clients may ask for JSONP with the `j` parameter, they may by default
+    pub fn jsonp(mut self, jsonp: bool) -> Self {
+        self.option.jsonp = jsonp;
+        self
+    }
+
+    /// How many messages from the application may wait for the transport of a socket
+    pub fn send_buffer_size(mut self, send_buffer_size: usize) -> Self {
+        self.option.send_buffer_size = send_buffer_size;
+        self
+    }
+
+    /// What `Connection::send` does once the send buffer is full, it waits by default
+    pub fn send_buffer_policy(mut self, send_buffer_policy: BufferPolicy) -> Self {
+        self.option.send_buffer_policy = send_buffer_policy;
+        self
+    }
+
+    /// How sids are issued, base64id style random ids by default
+    pub fn sid_generator(mut self, sid_generator: impl SidGenerator + 'static) -> Self {
+        self.option.sid_generator = Arc::new(sid_generator);
+        self
+    }
+
+    pub fn cors_middleware(mut self, cors_middleware: C) -> Self {
+        self.option.cors_middleware = Some(cors_middleware);
+        self
+    }
+
+    /// Set a cookie holding the sid on handshake responses
+    pub fn cookie(mut self, cookie: Cookie) -> Self {
+        self.option.cookie = Some(cookie);
+        self
+    }
+
+    /// Decide whether a handshake is accepted, before a sid is issued
+    ///
+    /// ```no_run
+    /// # use engineio_rs::server::*;
+    /// let option = ServerOption::<Fake, Fake>::builder()
+    ///     .allow_request(|req: HandshakeRequest| async move {
+    ///         match req.query.get("token") {
+    ///             Some(token) if token == "secret" => Authorization::Allow,
+    ///             _ => Authorization::Deny {
+    ///                 code: 4,
+    ///                 message: "Invalid token".to_string(),
+    ///             },
+    ///         }
+    ///     })
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    pub fn allow_request<F, Fut>(mut self, allow_request: F) -> Self
+    where
+        F: Fn(HandshakeRequest) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Authorization> + Send + 'static,
+    {
+        self.option.allow_request = Some(AllowRequest(Box::new(move |req| {
+            allow_request(req).boxed()
+        })));
+        self
+    }
+
+    /// Path engine.io is served under, `/engine.io` by default
+    pub fn path(mut self, path: &str) -> Self {
+        self.option.path = path.to_string();
+        self
+    }
+
+    /// Address `Server::listen` binds to, `0.0.0.0:3030` by default
+    pub fn addr(mut self, addr: impl Into<SocketAddr>) -> Self {
+        self.option.addr = addr.into();
+        self
+    }
+
+    pub fn build(self) -> Result<ServerOption<W, C>, ServerOptionError> {
+        let option = self.option;
+        let durations = [
+            ("ping_timeout", option.ping_timeout),
+            ("ping_interval", option.ping_interval),
+            ("upgrade_timeout", option.upgrade_timeout),
+            ("poll_duration", option.poll_duration),
+        ];
+        if let Some((name, _)) = durations
+            .iter()
+            .find(|(_, d)| *d == Duration::from_millis(0))
+        {
+            return Err(ServerOptionError::ZeroDuration(name));
+        }
+        if option.max_http_buffer_size == 0 {
+            return Err(ServerOptionError::ZeroBufferSize);
+        }
+        if option.send_buffer_size == 0 {
+            return Err(ServerOptionError::ZeroSendBufferSize);
+        }
+        let segments = option.path_segments();
+        if segments.is_empty() || option.path.contains(['?', '#']) {
+            return Err(ServerOptionError::InvalidPath(option.path));
+        }
+        if let Some(cookie) = option.cookie.as_ref().filter(|c| !c.is_valid()) {
+            return Err(ServerOptionError::InvalidCookie(cookie.name.clone()));
+        }
+        Ok(option)
+    }
 }
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct QueryParam {
     sid: Option<SID>,
     transport: Option<String>,
+    #[serde(rename = "EIO")]
+    eio: Option<String>,
+    b64: Option<String>,
+    /// Index of the JSONP callback
+    j: Option<String>,
 }
 
-type Clients = Arc<Mutex<HashMap<SID, util::BiChan<Message, Message>>>>;
+impl QueryParam {
+    /// Parse the query string of a request, which may be absent
+    pub fn parse(query: Option<&str>) -> Result<Self, serde_urlencoded::de::Error> {
+        serde_urlencoded::from_str(query.unwrap_or(""))
+    }
+
+    /// Protocol revision requested by the client, v3 unless `EIO=4` is given
+    pub fn protocol(&self) -> ProtocolVersion {
+        self.eio
+            .as_deref()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or_default()
+    }
+
+    /// Whether the client can receive binary polling payloads
+    pub fn supports_binary(&self) -> bool {
+        self.b64.is_none()
+    }
+
+    /// The JSONP callback, if the client polls with JSONP
+    pub fn jsonp(&self) -> Option<Jsonp> {
+        self.j.as_deref().and_then(Jsonp::new)
+    }
+}
+
+type Clients = Arc<Mutex<HashMap<SID, Arc<Client>>>>;
 
 #[derive(Debug)]
 pub struct Server<W, C>
@@ -69,8 +627,15 @@ where
     C: CORSMiddleware + Sync + Send + 'static,
 {
     clients: Clients,
-    phantom_ws: PhantomData<W>,
-    phantom_cors: PhantomData<C>,
+    pub(crate) option: Arc<ServerOption<W, C>>,
+    /// New sessions, queued until the application takes them with `connections`
+    connection_tx: mpsc::Sender<Connection>,
+    connection_rx: Arc<std::sync::Mutex<Option<mpsc::Receiver<Connection>>>>,
+    /// Set once `close` is called, new sessions are refused from then on
+    closing: Arc<AtomicBool>,
+    /// Becomes `true` once `close` is done
+    closed_tx: Arc<watch::Sender<bool>>,
+    closed_rx: watch::Receiver<bool>,
 }
 
 impl<W, C> Clone for Server<W, C>
@@ -81,8 +646,12 @@ where
     fn clone(&self) -> Self {
         Self {
             clients: self.clients.clone(),
-            phantom_ws: PhantomData,
-            phantom_cors: PhantomData,
+            option: self.option.clone(),
+            connection_tx: self.connection_tx.clone(),
+            connection_rx: self.connection_rx.clone(),
+            closing: self.closing.clone(),
+            closed_tx: self.closed_tx.clone(),
+            closed_rx: self.closed_rx.clone(),
         }
     }
 }
@@ -93,11 +662,7 @@ where
     C: CORSMiddleware + Sync + Send + 'static,
 {
     fn default() -> Self {
-        Self {
-            clients: Arc::new(Mutex::new(HashMap::default())),
-            phantom_ws: PhantomData,
-            phantom_cors: PhantomData,
-        }
+        Self::new(ServerOption::default())
     }
 }
 
@@ -106,198 +671,1306 @@ where
     W: WSEngine + Sync + Send + 'static,
     C: CORSMiddleware + Sync + Send + 'static,
 {
-    pub async fn listen() {
-        let handler = {
-            let server = Self::default();
-            let server = warp::any().map(move || server.clone());
-            let handle_polling_get = warp::path("engine.io")
-                .and(warp::path::end())
-                .and(server.clone())
-                .and(warp::query::<QueryParam>())
-                .and_then(Self::on_get);
-            let handle_polling_post = warp::path("engine.io")
-                .and(warp::path::end())
-                .and(server.clone())
-                .and(warp::query::<QueryParam>())
-                .and(warp::body::content_length_limit(1024 * 32))
-                .and(warp::body::bytes())
-                .and_then(Self::on_post);
-
-            let handle_ws = warp::path("engine.io")
-                .and(warp::path::end())
-                .and(warp::query::<QueryParam>())
-                .and(warp::ws())
-                .and(server)
-                .map(|param: QueryParam, ws: warp::ws::Ws, server: Self| {
-                    println!("ws: {:?}", ws);
-                    ws.on_upgrade(move |socket| server.on_ws_connected(socket, param))
-                });
-            handle_ws.or(handle_polling_post).or(handle_polling_get)
+    pub fn new(option: ServerOption<W, C>) -> Self {
+        let (connection_tx, connection_rx) = mpsc::channel(CONNECTION_CAPACITY);
+        let (closed_tx, closed_rx) = watch::channel(false);
+        Self {
+            clients: Arc::new(Mutex::new(HashMap::default())),
+            option: Arc::new(option),
+            connection_tx,
+            connection_rx: Arc::new(std::sync::Mutex::new(Some(connection_rx))),
+            closing: Arc::new(AtomicBool::new(false)),
+            closed_tx: Arc::new(closed_tx),
+            closed_rx,
+        }
+    }
+
+    /// Stream of the sessions opened by clients
+    ///
+    /// ```no_run
+    /// # use engineio_rs::server::*;
+    /// # use futures::StreamExt;
+    /// # async fn run() {
+    /// let server = Server::<Fake, Fake>::default();
+    /// let mut connections = server.connections().unwrap();
+    /// tokio::spawn(server.listen());
+    /// while let Some(mut connection) = connections.next().await {
+    ///     tokio::spawn(async move {
+    ///         while let Some(message) = connection.next().await {
+    ///             println!("{}: {:?}", connection.sid(), message);
+    ///         }
+    ///     });
+    /// }
+    /// # }
+    /// ```
+    ///
+    /// There is a single stream per `Server`, `None` is returned once it has been taken.
+    /// Sessions are queued until it is read, handshakes are refused with
+    /// `503 Service Unavailable` while `CONNECTION_CAPACITY` of them are waiting.
+    pub fn connections(&self) -> Option<mpsc::Receiver<Connection>> {
+        self.connection_rx.lock().unwrap().take()
+    }
+
+    /// Handle a request that was routed to the engine.io path
+    ///
+    /// This is the entry point for any HTTP stack, see `Server::filter` for warp
+    /// and the `Service` implementation for hyper. A WebSocket handshake is answered
+    /// with `101 Switching Protocols`; the connection is taken over once the response
+    /// has been written, so it has to reach hyper unchanged.
+    pub async fn handle_request(&self, req: Request<Body>) -> Response<Body> {
+        let cors = match &self.option.cors_middleware {
+            Some(cors) => cors,
+            None => return self.respond(req).await,
         };
-        warp::serve(handler).run(([0, 0, 0, 0], 3030)).await;
+        if req.method() == Method::OPTIONS {
+            return cors.preflight(req.headers());
+        }
+        let request_headers = req.headers().clone();
+        let mut response = self.respond(req).await;
+        response
+            .headers_mut()
+            .extend(cors.response_headers(&request_headers));
+        response
     }
 
-    async fn on_get(self, param: QueryParam) -> Result<String, Infallible> {
-        debug!("on_get: {:?}", param);
-        Ok(self.on_request(param, None, false).await)
+    async fn respond(&self, req: Request<Body>) -> Response<Body> {
+        let param = match QueryParam::parse(req.uri().query()) {
+            Ok(param) => param,
+            Err(e) => {
+                warn!("Malformed query {:?}: {}", req.uri().query(), e);
+                return error_response(VerifyError::BadRequest);
+            }
+        };
+        let upgrade = is_websocket(&req);
+        if let Err(e) = self.verify(&req, &param, upgrade).await {
+            warn!("Rejected {} {}: {}", req.method(), req.uri(), e);
+            return error_response(e);
+        }
+        if upgrade {
+            return self.accept_websocket(param, req);
+        }
+        match *req.method() {
+            Method::GET => self.clone().on_request(param, None, false).await,
+            Method::POST => {
+                let is_binary = req
+                    .headers()
+                    .get(CONTENT_TYPE)
+                    .is_some_and(|v| v == "application/octet-stream");
+                let body = read_body(req.into_body(), self.option.max_http_buffer_size).await;
+                match body {
+                    Ok(bytes) => {
+                        let bytes = match param.jsonp() {
+                            Some(_) => match polling_jsonp::decode_body(&bytes) {
+                                Ok(payload) => Bytes::from(payload),
+                                Err(e) => {
+                                    warn!("Malformed JSONP body {:?}: {}", param, e);
+                                    return error_response(VerifyError::BadRequest);
+                                }
+                            },
+                            None => bytes,
+                        };
+                        debug!("on_post: {:?}, bytes: {:?}", param, bytes);
+                        self.clone()
+                            .on_request(param, Some((bytes, is_binary)), true)
+                            .await
+                    }
+                    Err(response) => {
+                        // Whatever the session was sending is lost, it can not go on
+                        if let Some(sid) = &param.sid {
+                            if let Some(client) = self.client(sid).await {
+                                let _ = client
+                                    .tx
+                                    .clone()
+                                    .send(Message::Disconnect(CloseReason::MessageTooLarge))
+                                    .await;
+                            }
+                        }
+                        response
+                    }
+                }
+            }
+            _ => error_response(VerifyError::BadRequest),
+        }
     }
 
-    async fn on_post(self, param: QueryParam, bytes: bytes::Bytes) -> Result<String, Infallible> {
-        debug!("on_post: {:?}, bytes: {:?}", param, bytes);
-        Ok(self.on_request(param, Some(bytes), true).await)
+    /// Answer a WebSocket handshake and serve the connection once hyper upgrades it
+    fn accept_websocket(&self, param: QueryParam, req: Request<Body>) -> Response<Body> {
+        let key = match req.headers().typed_get::<SecWebsocketKey>() {
+            Some(key) => key,
+            None => return error_response(VerifyError::BadRequest),
+        };
+        let server = self.clone();
+        tokio::spawn(async move {
+            let upgraded = match req.into_body().on_upgrade().await {
+                Ok(upgraded) => upgraded,
+                Err(e) => {
+                    error!("{:?}", e);
+                    return;
+                }
+            };
+            let config = WebSocketConfig {
+                max_send_queue: None,
+                max_message_size: Some(server.option.max_http_buffer_size),
+                max_frame_size: Some(server.option.max_http_buffer_size),
+            };
+            let ws = WebSocketStream::from_raw_socket(upgraded, Role::Server, Some(config)).await;
+            let (tx, rx) = ws.split();
+            // `sink_map_err` may only map one error, the socket can see several on the way out
+            let tx = Box::pin(tx.sink_err_into::<TransportError>());
+            let rx = Box::pin(rx.map_err(TransportError::from));
+            server.on_ws_connected(param, tx, rx).await;
+        });
+        let mut response = Response::new(Body::empty());
+        *response.status_mut() = StatusCode::SWITCHING_PROTOCOLS;
+        let map = response.headers_mut();
+        map.typed_insert(headers::Connection::upgrade());
+        map.typed_insert(Upgrade::websocket());
+        map.typed_insert(SecWebsocketAccept::from(key));
+        response
     }
 
+    /// `data` is the POST body and whether it is a binary payload
     async fn on_request(
         self,
         param: QueryParam,
-        data: Option<bytes::Bytes>,
+        data: Option<(Bytes, bool)>,
         is_post: bool,
-    ) -> String {
+    ) -> Response<Body> {
         debug!(
             "http message: {:?}, data: {:?}, is_post: {}",
             param, data, is_post
         );
         match param.sid {
             Some(ref sid) if is_post => self.handle_xhr_post(sid, data).await,
-            Some(ref sid) if !is_post => self.handle_xhr_get(sid).await,
+            Some(ref sid) => self.handle_xhr_get(sid, param.jsonp()).await,
             // Send sid for handshaking
-            _ if !is_post => {
-                let sid = self.clone().handshake().await;
-                if let Some(ref client) = self.clients.lock().await.get(&sid) {
-                    let s = match client.rx.recv() {
-                        Ok(Message::Packet(s)) => s.encode(),
-                        Ok(Message::Payload(s)) => s.encode(),
-                        _ => String::new(),
-                    };
-                    debug!("handshake response {:?}", s);
-                    s
-                } else {
-                    warn!("client of sid ({:?}) is not found", sid);
-                    String::new()
+            None => {
+                let sid = match self
+                    .clone()
+                    .handshake(param.protocol(), param.supports_binary())
+                    .await
+                {
+                    Ok(sid) => sid,
+                    Err(e) => return e.response(),
+                };
+                match self.client(&sid).await {
+                    Some(client) => {
+                        let mut response = self.poll(&client, param.jsonp()).await;
+                        if let Some(cookie) = &self.option.cookie {
+                            response
+                                .headers_mut()
+                                .append(SET_COOKIE, cookie.header(&sid));
+                        }
+                        debug!("handshake response {:?}", response);
+                        response
+                    }
+                    None => {
+                        warn!("client of sid ({:?}) is not found", sid);
+                        error_response(VerifyError::UnknownSID)
+                    }
                 }
             }
-            _ => String::new(),
         }
     }
 
-    async fn handle_xhr_get(self, sid: &SID) -> String {
+    /// Look up a client, releasing the lock on `clients` before returning
+    async fn client(&self, sid: &SID) -> Option<Arc<Client>> {
+        self.clients.lock().await.get(sid).cloned()
+    }
+
+    async fn handle_xhr_get(self, sid: &SID, jsonp: Option<Jsonp>) -> Response<Body> {
         debug!("handle_xhr_get: sid = {:?}", sid);
-        if let Some(ref client) = self.clients.lock().await.get(sid) {
-            info!("Wait");
-            match client.rx.recv() {
-                Ok(Message::Packet(s)) => {
-                    use std::time;
-                    thread::sleep(time::Duration::from_millis(3000));
-                    info!("Response {:?}", s);
-                    s.encode()
-                }
-                Ok(Message::Payload(s)) => {
-                    use std::time;
-                    thread::sleep(time::Duration::from_millis(3000));
-                    info!("Response {:?}", s);
-                    s.encode()
-                }
-                Ok(s) => {
-                    warn!("Invalid message {:?}", s);
-                    String::new()
-                }
-                Err(e) => {
-                    error!("Error on handle_xhr_get {:?}", e);
-                    String::new()
-                }
-            }
+        if let Some(client) = self.client(sid).await {
+            self.poll(&client, jsonp).await
         } else {
             warn!("Invalid SID, client is not found");
-            String::new()
+            error_response(VerifyError::UnknownSID)
         }
     }
 
-    async fn handle_xhr_post(self, sid: &SID, data: Option<bytes::Bytes>) -> String {
-        if let Some(data) = data {
-            if let Some(ref client) = self.clients.lock().await.get(sid) {
-                if let Ok(s) = String::from_utf8(data.as_ref().to_vec()) {
-                    if let Some(p) = Message::to_message(&s) {
-                        if let Err(e) = client.tx.send(p) {
+    /// Flush the packets buffered for `client` as one payload,
+    /// answering with a noop if none arrive within `poll_duration`
+    async fn poll(&self, client: &Client, jsonp: Option<Jsonp>) -> Response<Body> {
+        let _polling = match client.polling.try_lock() {
+            Ok(guard) => guard,
+            Err(_) => {
+                warn!("Overlapping polling requests");
+                return error_response(VerifyError::BadRequest);
+            }
+        };
+        let drain = client
+            .buffer
+            .drain(self.option.max_http_buffer_size, client.protocol);
+        // The request counts as waiting from here, so the socket flushes into it
+        // A flush that is already queued covers this request too
+        let _ = client.tx.clone().try_send(Message::Flush);
+        match time::timeout(self.option.poll_duration, drain).await {
+            Ok(packets) => {
+                info!("Response {:?}", packets);
+                payload_response(client, Payload::from(packets), jsonp)
+            }
+            Err(_) => payload_response(client, Payload::from(vec![Packet::noop()]), jsonp),
+        }
+    }
+
+    async fn handle_xhr_post(self, sid: &SID, data: Option<(Bytes, bool)>) -> Response<Body> {
+        if let Some((data, is_binary)) = data {
+            let client = match self.client(sid).await {
+                Some(client) => client,
+                None => return error_response(VerifyError::UnknownSID),
+            };
+            let payload = if is_binary {
+                Payload::decode_binary(data.as_ref())
+            } else {
+                std::str::from_utf8(data.as_ref())
+                    .map_err(|_| DecodeError::InvalidUtf8)
+                    .and_then(|s| Payload::decode(s, client.protocol))
+            };
+            match payload {
+                Ok(payload) => {
+                    // The request waits for the socket to catch up with the client
+                    let mut incoming = client.incoming.clone();
+                    for packet in Vec::<Packet>::from(payload) {
+                        if let Err(e) = incoming.send(packet).await {
                             error!("{:?}", e)
                         }
                     }
                 }
+                Err(e) => {
+                    warn!("Malformed body from {:?}: {}", sid, e);
+                    return error_response(VerifyError::BadRequest);
+                }
             }
         }
-        "ok".to_string()
+        text_response("ok".to_string())
     }
 
-    async fn on_ws_connected(self, ws: WebSocket, param: QueryParam) {
-        // XHRと同様にClientからメッセージを待ちつつ、WSからデータがやって来ればClientに投げる
-        println!("on upgrade {:?}", ws);
-        println!("Param: {:?}", param);
-        // TODO Get client sid from query params
-        let sock = if let Some(_sid) = param.sid {
-            // TODO Get Socket by sid
-            unimplemented!()
-        // Socket::new(ws)
-        } else {
-            self.handshake_ws(ws).await
+    /// Serve a WebSocket connection, whichever framework accepted it
+    pub(crate) async fn on_ws_connected(self, param: QueryParam, tx: FrameSink, rx: FrameStream) {
+        debug!("on upgrade {:?}", param);
+        match param.sid {
+            Some(ref sid) => self.upgrade(sid, tx, rx).await,
+            None => match self.handshake_ws(&param, tx, rx).await {
+                Ok(sid) => debug!("websocket handshake {:?}", sid),
+                Err(e) => warn!("websocket handshake failed: {:?}", e),
+            },
+        }
+    }
+
+    /// Upgrade the polling session of `sid` to `ws`
+    ///
+    /// The client probes with `2probe` and gets `3probe` back, then sends `5` once it has
+    /// paused polling. The upgrade is aborted if that does not happen within `upgrade_timeout`.
+    async fn upgrade(self, sid: &SID, tx: FrameSink, rx: FrameStream) {
+        let client = match self.client(sid).await {
+            Some(client) => client,
+            None => {
+                warn!("Upgrade of unknown sid {:?}", sid);
+                return;
+            }
         };
-        unimplemented!()
-        // sock.run_ws().await;
+        if client.upgrading.swap(true, Ordering::SeqCst) {
+            warn!("{:?} is already upgrading", sid);
+            return;
+        }
+        let mut transport = websocket::WebSocket::new(tx, rx, client.protocol);
+        let probe = probe(&client, &mut transport);
+        match time::timeout(self.option.upgrade_timeout, probe).await {
+            Ok(true) => {
+                if client
+                    .tx
+                    .clone()
+                    .send(Message::Upgrade(Box::new(transport)))
+                    .await
+                    .is_ok()
+                {
+                    *client.transport.lock().unwrap() = websocket::NAME;
+                }
+                return;
+            }
+            Ok(false) => warn!("Upgrade of {:?} failed", sid),
+            Err(_) => warn!("Upgrade of {:?} timed out", sid),
+        }
+        client.upgrading.store(false, Ordering::SeqCst);
     }
 
-    async fn handshake_ws(self, ws: WSFilter) -> Socket<websocket::WebSocket> {
-        trace!("handshake");
-        // TODO Get transport from query params
-        // TODO Check binary is supported (binary mode if b64 is set true)
-
-        unimplemented!()
-        // let (ch1, ch2) = util::BiChan::new();
-        // let (tx, mut rx) = ws.split();
-        // let transport_ws = websocket::WebSocket::new(tx);
-        // thread::spawn(async move || {
-        //     while let Some(Ok(result)) = rx.next().await {
-        //         ch2.tx.send(Message::WebSocket(result));
-        //     }
-        // });
-        // let mut ret = Socket::<websocket::WebSocket>::new(transport_ws, ch1, 1500, 1500);
-        //
-        // self.clients.lock().await.insert(ret.sid(), ch2);
-        // debug!("#Client: {:?}", self.clients.lock().await.len());
-        // ret.on_open().await;
-        //
-        // ret
-    }
-
-    async fn handshake(self) -> SID {
-        trace!("handshake");
-        let (ch1, ch2) = util::BiChan::new();
+    /// Open a session on a WebSocket the client connected with directly
+    async fn handshake_ws(
+        self,
+        param: &QueryParam,
+        tx: FrameSink,
+        rx: FrameStream,
+    ) -> Result<SID, OpenError> {
+        let transport = websocket::WebSocket::new(tx, rx, param.protocol());
+        // The session never polls, so its polling state stays empty
+        let buffer = Arc::new(polling::Buffer::default());
+        let (incoming, _) = mpsc::channel(1);
+        self.open(
+            Box::new(transport),
+            websocket::NAME,
+            buffer,
+            incoming,
+            param.protocol(),
+            param.supports_binary(),
+        )
+        .await
+    }
+
+    /// Open a polling session
+    async fn handshake(
+        self,
+        protocol: ProtocolVersion,
+        supports_binary: bool,
+    ) -> Result<SID, OpenError> {
         let transport = polling::Polling::new();
-        let mut ret = Socket::<polling::Polling>::new(transport, ch1, 1500, 1500);
+        let buffer = transport.buffer();
+        let incoming = transport.incoming();
+        self.open(
+            Box::new(transport),
+            polling::NAME,
+            buffer,
+            incoming,
+            protocol,
+            supports_binary,
+        )
+        .await
+    }
 
-        self.clients.lock().await.insert(ret.sid(), ch2);
-        debug!("#Client: {:?}", self.clients.lock().await.len());
-        ret.on_open().await;
-        ret.sid()
+    /// Start the socket of a new session on `transport` and hand it to the application
+    async fn open(
+        self,
+        transport: DynTransport,
+        name: &'static str,
+        buffer: Arc<polling::Buffer>,
+        incoming: mpsc::Sender<Packet>,
+        protocol: ProtocolVersion,
+        supports_binary: bool,
+    ) -> Result<SID, OpenError> {
+        trace!("handshake");
+        let (tx, rx) = mpsc::channel(socket::COMMAND_CAPACITY);
+        let option = self.socket_option(protocol, name);
+        // Held until the client is inserted, so that no other session gets the same sid
+        // and the task can not remove it before
+        let mut clients = self.clients.lock().await;
+        // The request may have been verified before `close` started, checking here under the
+        // lock ensures that the session is either in the snapshot of `close` or never opened
+        if self.closing.load(Ordering::SeqCst) {
+            return Err(OpenError::Closing);
+        }
+        let sid = self.generate_sid(&clients)?;
+        let mut ret = Socket::<DynTransport>::new(sid.clone(), transport, rx, option);
+        let connection = ret.connection(tx.clone());
+        let closed = connection.closed();
+        // Queued before the socket runs, so that a refused session never starts. Once the
+        // application dropped the stream its sessions still run, without anyone to read them
+        if let Err(TrySendError::Full(_)) = self.connection_tx.clone().try_send(connection) {
+            warn!("{} sessions are waiting to be taken", CONNECTION_CAPACITY);
+            return Err(OpenError::Busy);
+        }
+        let (run, abort) = future::abortable(async move {
+            if let Err(e) = ret.on_open().await {
+                error!("{:?}", e);
+            }
+            ret.run().await;
+        });
+        let run = tokio::spawn(run);
+        let task = tokio::spawn({
+            let clients = self.clients.clone();
+            let buffer = buffer.clone();
+            let timeout = self.option.shutdown_timeout;
+            let sid = sid.clone();
+            async move {
+                // Awaited in its own task, so that the session is removed even if it panics
+                match run.await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(_)) => debug!("socket {:?} aborted", sid),
+                    Err(e) => error!("socket {:?} failed: {}", sid, e),
+                }
+                // Polling clients get the close packet with their next request
+                if closed.await.expects_close_packet()
+                    && time::timeout(timeout, buffer.flushed()).await.is_err()
+                {
+                    debug!("{:?} did not poll its close packet", sid);
+                }
+                clients.lock().await.remove(&sid);
+                debug!("#Client: {:?}", clients.lock().await.len());
+            }
+        });
+        clients.insert(
+            sid.clone(),
+            Arc::new(Client::new(
+                tx,
+                buffer,
+                incoming,
+                name,
+                protocol,
+                supports_binary,
+                (task, abort),
+            )),
+        );
+        debug!("#Client: {:?}", clients.len());
+        Ok(sid)
     }
 
-    /// Verify a request
-    /// 1. Check transport parameter
-    /// 2. Check Origin header
-    /// 3. Check sid
-    ///     - parameter must have `sid`
-    ///     - `transport` parameter must be matched with `transport` value set when connected
+    /// Draw a sid that is valid and not in use, giving up after a few attempts
+    fn generate_sid(&self, clients: &HashMap<SID, Arc<Client>>) -> Result<SID, OpenError> {
+        for _ in 0..SID_ATTEMPTS {
+            let sid = self.option.sid_generator.generate();
+            if !socket::is_valid_sid(&sid) {
+                error!("sid {:?} contains characters not allowed in URLs", sid);
+            } else if clients.contains_key(&sid) {
+                warn!("sid {:?} is already in use", sid);
+            } else {
+                return Ok(sid);
+            }
+        }
+        Err(OpenError::NoSid)
+    }
+
+    /// Settings of a socket opened on `transport`
+    fn socket_option(&self, protocol: ProtocolVersion, transport: &str) -> SocketOption {
+        SocketOption {
+            protocol,
+            ping_interval: self.option.ping_interval,
+            ping_timeout: self.option.ping_timeout,
+            upgrades: transports::upgrades(transport)
+                .iter()
+                .map(|t| t.to_string())
+                .collect(),
+            max_payload: self.option.max_http_buffer_size,
+            send_buffer_size: self.option.send_buffer_size,
+            send_buffer_policy: self.option.send_buffer_policy,
+        }
+    }
+
+    /// Verify a request, `upgrade` telling whether it asks for a WebSocket
+    /// 1. Check transport parameter, `j` being only allowed for polling when JSONP is enabled
+    /// 2. Check sid if given
+    ///     - the session must exist
+    ///     - `transport` parameter must be matched with the transport the session is on,
+    ///       unless the request upgrades it
+    /// 3. Check the handshake otherwise
     ///     - Method must be GET
-    ///     - TODO (when allowRequest is not empty)
-    fn verify(&self) -> VerifyResult {
+    ///     - `EIO` must be a supported protocol revision
+    ///     - New sessions must be accepted
+    ///     - The `allow_request` hook must allow it
+    pub(crate) async fn verify<B>(
+        &self,
+        req: &Request<B>,
+        param: &QueryParam,
+        upgrade: bool,
+    ) -> VerifyResult {
+        let transport = match param.transport.as_deref() {
+            Some(transport) if transports::NAMES.contains(&transport) => transport,
+            _ => return Err(VerifyError::UnknownTransport),
+        };
+        if upgrade != (transport == websocket::NAME) {
+            return Err(VerifyError::BadRequest);
+        }
+        if let Some(j) = &param.j {
+            if transport != polling::NAME || !self.option.jsonp || Jsonp::new(j).is_none() {
+                return Err(VerifyError::BadRequest);
+            }
+        }
+        if let Some(sid) = &param.sid {
+            let client = self.client(sid).await.ok_or(VerifyError::UnknownSID)?;
+            if !upgrade && *client.transport.lock().unwrap() != transport {
+                return Err(VerifyError::BadRequest);
+            }
+            return Ok(());
+        }
+        if req.method() != Method::GET {
+            return Err(VerifyError::BadHandshakeMethod);
+        }
+        if let Some(eio) = &param.eio {
+            eio.parse::<ProtocolVersion>()
+                .map_err(|_| VerifyError::UnsupportedProtocolVersion)?;
+        }
+        if self.closing.load(Ordering::SeqCst) {
+            return Err(VerifyError::Forbidden);
+        }
+        if let Some(allow_request) = &self.option.allow_request {
+            if let Authorization::Deny { code, message } =
+                (allow_request.0)(HandshakeRequest::new(req)).await
+            {
+                return Err(VerifyError::Denied { code, message });
+            }
+        }
         Ok(())
     }
 
-    /// Close all clients
-    pub fn close(&mut self) {
-        unimplemented!()
+    /// Close every session, then stop listening
+    ///
+    /// Each socket sends a close packet, which also answers the polling requests in flight.
+    /// Sessions are given `shutdown_timeout` to receive it before they are dropped.
+    pub async fn close(&self) {
+        self.closing.store(true, Ordering::SeqCst);
+        let clients: Vec<Arc<Client>> = self.clients.lock().await.values().cloned().collect();
+        // Sending waits for sockets to take commands, so it is bounded by the timeout too.
+        // Tasks end once their close packet is delivered.
+        let flush = async {
+            for client in clients.iter() {
+                let _ = client
+                    .tx
+                    .clone()
+                    .send(Message::Disconnect(CloseReason::ServerShutdown))
+                    .await;
+            }
+            for client in clients.iter() {
+                if let Some(task) = client.task.lock().await.take() {
+                    let _ = task.await;
+                }
+            }
+        };
+        if time::timeout(self.option.shutdown_timeout, flush)
+            .await
+            .is_err()
+        {
+            warn!("Not every session received its close packet");
+            // Sockets still sending or waiting for the application would otherwise outlive
+            // the server, and keep pinging
+            for client in clients.iter() {
+                client.abort.abort();
+            }
+        }
+        self.clients.lock().await.clear();
+        let _ = self.closed_tx.broadcast(true);
+    }
+
+    /// Resolves once `close` is done, to shut down the HTTP server serving this one
+    ///
+    /// `listen` does so on its own, a hyper server can be given this future as
+    /// `with_graceful_shutdown(server.closed())`.
+    pub fn closed(&self) -> impl Future<Output = ()> + Send + 'static {
+        let mut closed = self.closed_rx.clone();
+        async move {
+            while let Some(done) = closed.recv().await {
+                if done {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Whether `req` asks to open a WebSocket
+fn is_websocket(req: &Request<Body>) -> bool {
+    req.method() == Method::GET
+        && req
+            .headers()
+            .get(UPGRADE)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v.eq_ignore_ascii_case("websocket"))
+}
+
+/// Read a request body, answering with `413 Payload Too Large` past `limit` bytes
+async fn read_body(mut body: Body, limit: usize) -> Result<Bytes, Response<Body>> {
+    let mut buf = BytesMut::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|e| {
+            warn!("Failed to read body: {}", e);
+            error_response(VerifyError::BadRequest)
+        })?;
+        if buf.len() + chunk.len() > limit {
+            let mut response = Response::new(Body::empty());
+            *response.status_mut() = StatusCode::PAYLOAD_TOO_LARGE;
+            return Err(response);
+        }
+        buf.extend_from_slice(&chunk);
     }
+    Ok(buf.freeze())
+}
+
+/// Respond to a polling request with `payload`, in the binary format if the client supports it
+///
+/// JSONP clients can only be sent text, wrapped into a call to their callback.
+fn payload_response(client: &Client, payload: Payload, jsonp: Option<Jsonp>) -> Response<Body> {
+    if let Some(jsonp) = jsonp {
+        let script = jsonp.wrap(&payload.encode(client.protocol));
+        response(script, "text/javascript; charset=UTF-8")
+    } else if client.protocol == ProtocolVersion::V3
+        && client.supports_binary
+        && payload.has_binary()
+    {
+        response(payload.encode_binary(), "application/octet-stream")
+    } else {
+        text_response(payload.encode(client.protocol))
+    }
+}
+
+fn text_response(body: String) -> Response<Body> {
+    response(body, "text/plain; charset=UTF-8")
+}
+
+fn response(body: impl Into<Body>, content_type: &'static str) -> Response<Body> {
+    let mut response = Response::new(body.into());
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+    response
+}
+
+/// Respond with one of the protocol's errors
+pub(crate) fn error_response(error: VerifyError) -> Response<Body> {
+    let body = serde_json::json!({
+        "code": error.code(),
+        "message": error.to_string(),
+    });
+    let mut response = response(body.to_string(), "application/json");
+    *response.status_mut() = error.status();
+    response
+}
+
+/// Exchange the upgrade probes over `ws`, `true` once the client sent the upgrade packet
+async fn probe(client: &Client, ws: &mut websocket::WebSocket) -> bool {
+    let mut probed = false;
+    while let Some(packet) = ws.recv().await {
+        let packet = match packet {
+            Ok(packet) => packet,
+            Err(e) => {
+                warn!("{:?}", e);
+                return false;
+            }
+        };
+        if packet.is_probe() {
+            if let Err(e) = ws.send_packet(Packet::probe()).await {
+                error!("{:?}", e);
+                return false;
+            }
+            // Release the pending polling request so that the client can pause polling
+            client.buffer.push(Packet::noop());
+            probed = true;
+        } else if packet.typ == PacketType::Upgrade && probed {
+            return true;
+        } else {
+            warn!("Unexpected packet during upgrade {:?}", packet);
+            return false;
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cors::Cors;
+    use crate::packet::PacketData;
+    use crate::socket::ReadyState;
+    use crate::transports::websocket::Frame;
+
+    use futures::channel::mpsc as channel;
+
+    fn builder() -> ServerOptionBuilder<Fake, Fake> {
+        ServerOption::builder()
+    }
+
+    /// Request opening a v4 polling session
+    fn handshake_request() -> Request<Body> {
+        Request::get("/engine.io/?EIO=4&transport=polling")
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    /// Open a polling session, `connections` being those of `server`
+    async fn handshake(
+        server: &Server<Fake, Fake>,
+        connections: &mut mpsc::Receiver<Connection>,
+    ) -> Connection {
+        let res = server.handle_request(handshake_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        connections.recv().await.unwrap()
+    }
+
+    /// Where the client of `connection` polls and posts
+    fn session_uri(connection: &Connection) -> String {
+        format!(
+            "/engine.io/?EIO=4&transport=polling&sid={}",
+            connection.sid()
+        )
+    }
+
+    #[test]
+    fn option_validation() {
+        assert!(builder().build().is_ok());
+        assert_eq!(
+            builder()
+                .ping_timeout(Duration::from_millis(0))
+                .build()
+                .unwrap_err(),
+            ServerOptionError::ZeroDuration("ping_timeout")
+        );
+        assert_eq!(
+            builder().max_http_buffer_size(0).build().unwrap_err(),
+            ServerOptionError::ZeroBufferSize
+        );
+        assert_eq!(
+            builder().send_buffer_size(0).build().unwrap_err(),
+            ServerOptionError::ZeroSendBufferSize
+        );
+        assert_eq!(
+            builder().path("/").build().unwrap_err(),
+            ServerOptionError::InvalidPath("/".to_string())
+        );
+        assert_eq!(
+            builder()
+                .cookie(Cookie::default().name("a b"))
+                .build()
+                .unwrap_err(),
+            ServerOptionError::InvalidCookie("a b".to_string())
+        );
+    }
+
+    #[test]
+    fn option_path_segments() {
+        let option = builder().path("/socket/engine.io/").build().unwrap();
+        assert_eq!(option.path_segments(), vec!["socket", "engine.io"]);
+    }
+
+    #[tokio::test]
+    async fn handle_request_polling() {
+        let server = Server::<Fake, Fake>::default();
+        let res = server.handle_request(handshake_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        assert!(body.starts_with(b"0{"));
+        let sid = server.clients.lock().await.keys().next().cloned().unwrap();
+        let connection = server.connections().unwrap().recv().await.unwrap();
+        assert_eq!(connection.sid(), &sid);
+        assert!(server.connections().is_none());
+
+        let req = Request::post(format!("/engine.io/?EIO=4&transport=polling&sid={}", sid))
+            .body(Body::from(vec![b'4'; 2_000_000]))
+            .unwrap();
+        let res = server.handle_request(req).await;
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(connection.closed().await, CloseReason::MessageTooLarge);
+
+        let req = Request::get("/engine.io/?EIO=4&sid=a&sid=b")
+            .body(Body::empty())
+            .unwrap();
+        let res = server.handle_request(req).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn close_completes_pending_polls() {
+        let option = builder()
+            .shutdown_timeout(Duration::from_millis(200))
+            .build()
+            .unwrap();
+        let server = Server::new(option);
+        let mut connections = server.connections().unwrap();
+        let connection = handshake(&server, &mut connections).await;
+        let uri = session_uri(&connection);
+        let poll = tokio::spawn({
+            let server = server.clone();
+            let req = Request::get(uri).body(Body::empty()).unwrap();
+            async move { server.handle_request(req).await }
+        });
+        server.close().await;
+        let res = poll.await.unwrap();
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        assert_eq!(body.as_ref(), b"1");
+        assert_eq!(connection.closed().await, CloseReason::ServerShutdown);
+        assert!(server.clients.lock().await.is_empty());
+        server.closed().await;
+
+        let res = server.handle_request(handshake_request()).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn close_aborts_stuck_sockets() {
+        let option = builder()
+            .shutdown_timeout(Duration::from_millis(50))
+            .build()
+            .unwrap();
+        let server = Server::new(option);
+        let mut connections = server.connections().unwrap();
+        // The client reads nothing, so sending blocks once the open packet is in the channel
+        let (tx, _sent) = channel::channel(0);
+        let tx = tx.sink_map_err(|e| TransportError::WebSocketError(Box::new(e)));
+        let (_frames, rx) = channel::unbounded();
+        let param = QueryParam::parse(Some("EIO=4&transport=websocket")).unwrap();
+        server
+            .clone()
+            .on_ws_connected(param, Box::pin(tx), Box::pin(rx))
+            .await;
+        let connection = connections.recv().await.unwrap();
+        connection.send("stuck").await.unwrap();
+
+        server.close().await;
+        let reason = time::timeout(Duration::from_millis(500), connection.closed())
+            .await
+            .expect("socket was not aborted");
+        assert_eq!(reason, CloseReason::TransportClose);
+        assert!(server.clients.lock().await.is_empty());
+    }
+
+    #[derive(Debug)]
+    struct Sequence(std::sync::Mutex<Vec<&'static str>>);
+
+    impl SidGenerator for Sequence {
+        fn generate(&self) -> SID {
+            self.0.lock().unwrap().remove(0).to_string()
+        }
+    }
+
+    #[tokio::test]
+    async fn sid_collision_retries() {
+        let option = builder()
+            .sid_generator(Sequence(std::sync::Mutex::new(vec!["a", "a", "b"])))
+            .build()
+            .unwrap();
+        let server = Server::new(option);
+        let mut connections = server.connections().unwrap();
+        for sid in ["a", "b"].iter() {
+            assert_eq!(handshake(&server, &mut connections).await.sid(), sid);
+        }
+    }
+
+    #[tokio::test]
+    async fn invalid_sids_are_not_issued() {
+        let option = builder()
+            .sid_generator(Sequence(std::sync::Mutex::new(vec![
+                "node\u{1}1",
+                "a; Domain=evil",
+                "",
+                "node-1",
+            ])))
+            .cookie(Cookie::default())
+            .build()
+            .unwrap();
+        let server = Server::new(option);
+        let res = server.handle_request(handshake_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            res.headers()[SET_COOKIE],
+            "io=node-1; Path=/; HttpOnly; SameSite=Lax"
+        );
+
+        let option = builder()
+            .sid_generator(Sequence(std::sync::Mutex::new(vec!["a;b"; SID_ATTEMPTS])))
+            .build()
+            .unwrap();
+        let server = Server::new(option);
+        let res = server.handle_request(handshake_request()).await;
+        assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(server.clients.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn waiting_connections_are_bounded() {
+        let server = Server::<Fake, Fake>::default();
+        for _ in 0..CONNECTION_CAPACITY {
+            let res = server.handle_request(handshake_request()).await;
+            assert_eq!(res.status(), StatusCode::OK);
+        }
+        let res = server.handle_request(handshake_request()).await;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(server.clients.lock().await.len(), CONNECTION_CAPACITY);
+
+        let mut connections = server.connections().unwrap();
+        connections.recv().await.unwrap();
+        let res = server.handle_request(handshake_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        // Sessions still open without the application, as before it took the stream
+        drop(connections);
+        let res = server.handle_request(handshake_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn no_session_opens_while_closing() {
+        let server = Server::<Fake, Fake>::default();
+        // As if the handshake had been verified just before
+        server.closing.store(true, Ordering::SeqCst);
+        assert_eq!(
+            server.clone().handshake(ProtocolVersion::V4, true).await,
+            Err(OpenError::Closing)
+        );
+        assert!(server.clients.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn client_close_removes_session() {
+        let server = Server::<Fake, Fake>::default();
+        let mut connections = server.connections().unwrap();
+        let connection = handshake(&server, &mut connections).await;
+        let uri = session_uri(&connection);
+        let req = Request::post(&uri).body(Body::from("1")).unwrap();
+        let res = server.handle_request(req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(connection.closed().await, CloseReason::ClientClose);
+        assert_eq!(connection.ready_state(), ReadyState::Closed);
+
+        // The client left, so the session goes away without waiting for a close packet
+        // to be polled, long before `shutdown_timeout`
+        removed(&server, connection.sid()).await;
+        assert_eq!(
+            error_code(&server, Method::GET, &uri).await,
+            (StatusCode::BAD_REQUEST, 1)
+        );
+    }
+
+    /// Wait for the session of `sid` to be dropped
+    async fn removed(server: &Server<Fake, Fake>, sid: &SID) {
+        time::timeout(Duration::from_millis(500), async {
+            while server.clients.lock().await.contains_key(sid) {
+                time::delay_for(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .expect("session was not removed");
+    }
+
+    /// Both ends of a WebSocket, the server's and the frames the client sends and receives
+    fn websocket() -> (
+        FrameSink,
+        FrameStream,
+        channel::UnboundedSender<std::result::Result<Frame, TransportError>>,
+        channel::UnboundedReceiver<Frame>,
+    ) {
+        let (tx, sent) = channel::unbounded();
+        let tx = tx.sink_map_err(|e| TransportError::WebSocketError(Box::new(e)));
+        let (frames, rx) = channel::unbounded();
+        (Box::pin(tx), Box::pin(rx), frames, sent)
+    }
+
+    /// Text of the next frame the client receives
+    async fn next_text(sent: &mut channel::UnboundedReceiver<Frame>) -> String {
+        match time::timeout(Duration::from_millis(500), sent.next()).await {
+            Ok(Some(Frame::Text(text))) => text,
+            frame => panic!("expected a text frame, got {:?}", frame),
+        }
+    }
+
+    #[tokio::test]
+    async fn websocket_session() {
+        let server = Server::<Fake, Fake>::default();
+        let mut connections = server.connections().unwrap();
+        let (tx, rx, frames, mut sent) = websocket();
+        let param = QueryParam::parse(Some("EIO=4&transport=websocket")).unwrap();
+        server.clone().on_ws_connected(param, tx, rx).await;
+
+        let open = next_text(&mut sent).await;
+        assert!(open.starts_with("0{"));
+        assert!(open.contains(r#""upgrades":[]"#));
+        let mut connection = connections.recv().await.unwrap();
+        frames.unbounded_send(Ok(Frame::text("4hello"))).unwrap();
+        assert_eq!(
+            connection.next().await,
+            Some(PacketData::Text("hello".to_string()))
+        );
+        connection.send("world").await.unwrap();
+        assert_eq!(next_text(&mut sent).await, "4world");
+
+        frames.unbounded_send(Ok(Frame::Close(None))).unwrap();
+        assert_eq!(connection.closed().await, CloseReason::TransportClose);
+        removed(&server, connection.sid()).await;
+        assert_eq!(
+            error_code(&server, Method::GET, &session_uri(&connection)).await,
+            (StatusCode::BAD_REQUEST, 1)
+        );
+
+        // The connection drops without a close frame
+        let (tx, rx, frames, sent) = websocket();
+        let param = QueryParam::parse(Some("EIO=4&transport=websocket")).unwrap();
+        server.clone().on_ws_connected(param, tx, rx).await;
+        let connection = connections.recv().await.unwrap();
+        drop((frames, sent));
+        assert_eq!(connection.closed().await, CloseReason::TransportClose);
+        removed(&server, connection.sid()).await;
+    }
+
+    /// Body of a polling request to `uri`
+    async fn poll(server: Server<Fake, Fake>, uri: String) -> Bytes {
+        let req = Request::get(uri).body(Body::empty()).unwrap();
+        let res = server.handle_request(req).await;
+        hyper::body::to_bytes(res.into_body()).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn upgrade_to_websocket() {
+        let server = Server::<Fake, Fake>::default();
+        let mut connections = server.connections().unwrap();
+        let mut connection = handshake(&server, &mut connections).await;
+        let uri = session_uri(&connection);
+        let pending = tokio::spawn(poll(server.clone(), uri.clone()));
+
+        let (tx, rx, frames, mut sent) = websocket();
+        let param = QueryParam::parse(Some(&format!(
+            "EIO=4&transport=websocket&sid={}",
+            connection.sid()
+        )))
+        .unwrap();
+        let upgrade = tokio::spawn(server.clone().on_ws_connected(param, tx, rx));
+        frames.unbounded_send(Ok(Frame::text("2probe"))).unwrap();
+        assert_eq!(next_text(&mut sent).await, "3probe");
+        // The noop lets the client pause polling
+        assert_eq!(pending.await.unwrap().as_ref(), b"6");
+        frames.unbounded_send(Ok(Frame::text("5"))).unwrap();
+        upgrade.await.unwrap();
+
+        let client = server.client(connection.sid()).await.unwrap();
+        assert_eq!(*client.transport.lock().unwrap(), websocket::NAME);
+        connection.send("world").await.unwrap();
+        assert_eq!(next_text(&mut sent).await, "4world");
+        frames.unbounded_send(Ok(Frame::text("4hello"))).unwrap();
+        assert_eq!(
+            connection.next().await,
+            Some(PacketData::Text("hello".to_string()))
+        );
+        // Polling is over for this session
+        assert_eq!(
+            error_code(&server, Method::GET, &uri).await,
+            (StatusCode::BAD_REQUEST, 3)
+        );
+    }
+
+    #[tokio::test]
+    async fn upgrade_times_out() {
+        let option = builder()
+            .upgrade_timeout(Duration::from_millis(50))
+            .build()
+            .unwrap();
+        let server = Server::new(option);
+        let mut connections = server.connections().unwrap();
+        let connection = handshake(&server, &mut connections).await;
+        let uri = session_uri(&connection);
+
+        let (tx, rx, frames, mut sent) = websocket();
+        let param = QueryParam::parse(Some(&format!(
+            "EIO=4&transport=websocket&sid={}",
+            connection.sid()
+        )))
+        .unwrap();
+        let upgrade = tokio::spawn(server.clone().on_ws_connected(param, tx, rx));
+        frames.unbounded_send(Ok(Frame::text("2probe"))).unwrap();
+        assert_eq!(next_text(&mut sent).await, "3probe");
+        // The client never sends the upgrade packet
+        upgrade.await.unwrap();
+
+        let client = server.client(connection.sid()).await.unwrap();
+        assert!(!client.upgrading.load(Ordering::SeqCst));
+        assert_eq!(*client.transport.lock().unwrap(), polling::NAME);
+        assert_eq!(poll(server.clone(), uri.clone()).await.as_ref(), b"6");
+        connection.send("hello").await.unwrap();
+        assert_eq!(poll(server.clone(), uri).await.as_ref(), b"4hello");
+    }
+
+    async fn error_code(
+        server: &Server<Fake, Fake>,
+        method: Method,
+        uri: &str,
+    ) -> (StatusCode, u64) {
+        let req = Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(Body::empty())
+            .unwrap();
+        let res = server.handle_request(req).await;
+        let status = res.status();
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        (status, body["code"].as_u64().unwrap())
+    }
+
+    #[tokio::test]
+    async fn verify_error_codes() {
+        let server = Server::<Fake, Fake>::default();
+        let bad_request = StatusCode::BAD_REQUEST;
+        assert_eq!(
+            error_code(&server, Method::GET, "/engine.io/?EIO=4&transport=flash").await,
+            (bad_request, 0)
+        );
+        assert_eq!(
+            error_code(
+                &server,
+                Method::GET,
+                "/engine.io/?EIO=4&transport=polling&sid=x"
+            )
+            .await,
+            (bad_request, 1)
+        );
+        assert_eq!(
+            error_code(&server, Method::POST, "/engine.io/?EIO=4&transport=polling").await,
+            (bad_request, 2)
+        );
+        assert_eq!(
+            error_code(
+                &server,
+                Method::GET,
+                "/engine.io/?EIO=4&transport=websocket"
+            )
+            .await,
+            (bad_request, 3)
+        );
+        assert_eq!(
+            error_code(&server, Method::GET, "/engine.io/?EIO=5&transport=polling").await,
+            (bad_request, 5)
+        );
+
+        server.close().await;
+        assert_eq!(
+            error_code(&server, Method::GET, "/engine.io/?EIO=4&transport=polling").await,
+            (StatusCode::FORBIDDEN, 4)
+        );
+    }
+
+    #[tokio::test]
+    async fn allow_request_hook() {
+        let option = builder()
+            .allow_request(|req: HandshakeRequest| async move {
+                let token = req.query.get("token").map(|t| t.as_str());
+                let user = req.cookies.get("user").map(|u| u.as_str());
+                if token == Some("secret") && user == Some("alice") {
+                    Authorization::Allow
+                } else {
+                    Authorization::Deny {
+                        code: 42,
+                        message: "Invalid token".to_string(),
+                    }
+                }
+            })
+            .build()
+            .unwrap();
+        let server = Server::new(option);
+        assert_eq!(
+            error_code(
+                &server,
+                Method::GET,
+                "/engine.io/?EIO=4&transport=polling&token=guess"
+            )
+            .await,
+            (StatusCode::FORBIDDEN, 42)
+        );
+
+        let req = Request::get("/engine.io/?EIO=4&transport=polling&token=secret")
+            .header("cookie", "theme=dark; user=alice")
+            .body(Body::empty())
+            .unwrap();
+        let res = server.handle_request(req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn cors_headers() {
+        let option = ServerOption::<Fake, Cors>::builder()
+            .cors_middleware(Cors::origins(vec!["https://a.example"]))
+            .build()
+            .unwrap();
+        let server = Server::new(option);
+        let req = Request::builder()
+            .method(Method::OPTIONS)
+            .uri("/engine.io/?EIO=4&transport=polling")
+            .header("origin", "https://a.example")
+            .body(Body::empty())
+            .unwrap();
+        let res = server.handle_request(req).await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert_eq!(
+            res.headers()["access-control-allow-origin"],
+            "https://a.example"
+        );
+
+        let req = Request::get("/engine.io/?EIO=4&transport=polling")
+            .header("origin", "https://a.example")
+            .body(Body::empty())
+            .unwrap();
+        let res = server.handle_request(req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            res.headers()["access-control-allow-origin"],
+            "https://a.example"
+        );
+    }
+
+    #[tokio::test]
+    async fn handshake_cookie() {
+        let res = Server::<Fake, Fake>::default()
+            .handle_request(handshake_request())
+            .await;
+        assert!(!res.headers().contains_key(SET_COOKIE));
+
+        let option = builder().cookie(Cookie::default()).build().unwrap();
+        let res = Server::new(option)
+            .handle_request(handshake_request())
+            .await;
+        let cookie = res.headers()[SET_COOKIE].to_str().unwrap().to_string();
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let sid = serde_json::from_slice::<serde_json::Value>(&body[1..]).unwrap()["sid"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        assert_eq!(
+            cookie,
+            format!("io={}; Path=/; HttpOnly; SameSite=Lax", sid)
+        );
+
+        let option = builder()
+            .cookie(
+                Cookie::default()
+                    .name("lb")
+                    .path(None)
+                    .http_only(false)
+                    .same_site(Some(SameSite::None))
+                    .secure(true),
+            )
+            .build()
+            .unwrap();
+        let res = Server::new(option)
+            .handle_request(handshake_request())
+            .await;
+        let cookie = res.headers()[SET_COOKIE].to_str().unwrap();
+        assert!(cookie.starts_with("lb="));
+        assert!(cookie.ends_with("; SameSite=None; Secure"));
+    }
+
+    #[tokio::test]
+    async fn jsonp_polling() {
+        let server = Server::<Fake, Fake>::default();
+        let req = Request::get("/engine.io/?EIO=3&transport=polling&j=0")
+            .body(Body::empty())
+            .unwrap();
+        let res = server.handle_request(req).await;
+        assert_eq!(
+            res.headers()[CONTENT_TYPE],
+            "text/javascript; charset=UTF-8"
+        );
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        assert!(body.starts_with(br#"___eio[0](""#));
+        let mut connection = server.connections().unwrap().recv().await.unwrap();
+
+        let uri = format!(
+            "/engine.io/?EIO=3&transport=polling&j=0&sid={}",
+            connection.sid()
+        );
+        let req = Request::post(uri)
+            .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(Body::from("d=6%3A4hello"))
+            .unwrap();
+        let res = server.handle_request(req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            connection.next().await,
+            Some(PacketData::Text("hello".to_string()))
+        );
+
+        for j in ["", "a", "0);alert(1"].iter() {
+            let uri = format!("/engine.io/?EIO=3&transport=polling&j={}", j);
+            assert_eq!(
+                error_code(&server, Method::GET, &uri).await,
+                (StatusCode::BAD_REQUEST, 3)
+            );
+        }
 
-    pub fn handle_request(&self) {
-        // Verify the request
-        // Exec callback function with corresponding sid if the callback exists
-        unimplemented!()
+        let server = Server::new(builder().jsonp(false).build().unwrap());
+        let req = Request::get("/engine.io/?EIO=3&transport=polling&j=0")
+            .body(Body::empty())
+            .unwrap();
+        let res = server.handle_request(req).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
     }
 }
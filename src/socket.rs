@@ -1,58 +1,156 @@
-use std::time::Duration;
+use crate::connection::Connection;
+use crate::packet::{Packet, PacketData, PacketType, ProtocolVersion, WelcomeMessage};
+use crate::send_buffer::{BufferPolicy, SendBuffer};
+use std::fmt;
+use std::sync::Arc;
 
-use crate::packet::{Packet, PacketType, Payload};
-use crate::transports::{polling, websocket, Transport};
-use crate::util;
+use crate::transports::{DynTransport, Transport, TransportError};
 
 use async_trait::async_trait;
-use crossbeam::channel::{select, tick, unbounded};
-use log::{debug, error, trace, warn};
-use rand::prelude::*;
+use futures::channel::oneshot;
+use log::{debug, error, trace};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::sync::watch;
+use tokio::time::{self, Duration, Instant};
 
 pub type SID = String;
 
-const BASE_STR: &str = "ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
+/// Issues the sids of new sessions
+///
+/// Sids travel in URLs and cookies, so they may only contain ASCII letters, digits and `-._~`.
+/// The server retries with a new sid if one is not valid or already in use.
+/// Implement it to embed routing information, e.g. the id of the node serving the session:
+///
+/// ```
+/// # use engineio_rs::socket::*;
+/// #[derive(Debug)]
+/// struct NodeSid(u16);
+///
+/// impl SidGenerator for NodeSid {
+///     fn generate(&self) -> SID {
+///         format!("{:04x}-{}", self.0, Base64Id.generate())
+///     }
+/// }
+/// assert!(NodeSid(7).generate().starts_with("0007-"));
+/// ```
+pub trait SidGenerator: fmt::Debug + Send + Sync {
+    fn generate(&self) -> SID;
+}
+
+/// Whether `sid` can be sent as is in a query string and a cookie
+pub(crate) fn is_valid_sid(sid: &str) -> bool {
+    !sid.is_empty()
+        && sid
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-._~".contains(c))
+}
+
+/// Sids in the style of base64id: 15 bytes from the OS random number generator,
+/// encoded as 20 characters of URL-safe base64
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Base64Id;
 
-pub fn generate_sid() -> SID {
-    let mut rng = rand::thread_rng();
-    String::from_utf8(
-        BASE_STR
-            .as_bytes()
-            .choose_multiple(&mut rng, 20)
-            .cloned()
-            .collect(),
-    )
-    .unwrap()
+impl SidGenerator for Base64Id {
+    fn generate(&self) -> SID {
+        let mut bytes = [0; 15];
+        OsRng.fill_bytes(&mut bytes);
+        base64::encode_config(bytes, base64::URL_SAFE)
+    }
 }
 
+/// How many commands may wait for a socket, see `Message`
+pub(crate) const COMMAND_CAPACITY: usize = 16;
+
+/// How many messages from the client may wait for the application
+/// before the socket stops reading its transport
+const MESSAGE_CAPACITY: usize = 64;
+
 /// Messages used for communication between `Server` and `Socket`s
-#[derive(Debug)]
+///
+/// Packets from the client do not go through here, the socket reads them from its transport.
 pub enum Message {
-    Packet(Packet),
-    Payload(Payload),
-    Close,
+    /// The client completed an upgrade to this transport
+    Upgrade(DynTransport),
+    /// A polling request is waiting, buffered packets can be sent
+    Flush,
+    /// The application or the server closes the socket
+    Disconnect(CloseReason),
 }
 
-impl Message {
-    pub fn to_message(s: &str) -> Option<Self> {
-        match Packet::decode(s) {
-            Ok(p) => {
-                return Some(Message::Packet(p));
-            }
-            Err(e) => {
-                warn!("{:?}", e);
-            }
-        };
-        match Payload::decode(s) {
-            Ok(p) => {
-                return Some(Message::Payload(p));
-            }
-            Err(e) => {
-                warn!("{:?}", e);
-            }
+impl fmt::Debug for Message {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Upgrade(_) => f.write_str("Upgrade"),
+            Self::Flush => f.write_str("Flush"),
+            Self::Disconnect(r) => f.debug_tuple("Disconnect").field(r).finish(),
+        }
+    }
+}
+
+/// Why a socket was closed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CloseReason {
+    /// The client sent a close packet
+    ClientClose,
+    /// The connection of the client went away
+    TransportClose,
+    /// The transport failed, e.g. the client sent something that is not a packet
+    TransportError,
+    /// The client did not answer heartbeats in time
+    PingTimeout,
+    /// The application closed the socket
+    ForcedClose,
+    /// The server is shutting down, see `Server::close`
+    ServerShutdown,
+    /// The client sent more than `max_http_buffer_size` at once
+    MessageTooLarge,
+    /// The client did not keep up with the messages sent to it, see `BufferPolicy::Disconnect`
+    SendBufferFull,
+}
+
+impl fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let reason = match self {
+            Self::ClientClose => "client close",
+            Self::TransportClose => "transport close",
+            Self::TransportError => "transport error",
+            Self::PingTimeout => "ping timeout",
+            Self::ForcedClose => "forced close",
+            Self::ServerShutdown => "server shutdown",
+            Self::MessageTooLarge => "message too large",
+            Self::SendBufferFull => "send buffer full",
         };
-        return None;
+        f.write_str(reason)
+    }
+}
+
+impl CloseReason {
+    /// Whether the client is still there to receive what was sent before the close packet
+    fn is_graceful(self) -> bool {
+        matches!(self, Self::ForcedClose | Self::ServerShutdown)
     }
+
+    /// Whether the client should get a close packet,
+    /// it already left if it sent one itself or its transport is gone
+    pub(crate) fn expects_close_packet(self) -> bool {
+        !matches!(
+            self,
+            Self::ClientClose | Self::TransportClose | Self::TransportError
+        )
+    }
+}
+
+/// Lifecycle of a socket
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReadyState {
+    /// The open packet has not been sent yet
+    Opening,
+    Open,
+    /// Pending messages and the close packet are being sent
+    Closing,
+    Closed,
 }
 
 // TODO Refine error type
@@ -69,187 +167,528 @@ pub trait EngineIOSocket {
     async fn run(mut self);
 }
 
+/// Settings of a socket, advertised to the client in the open packet
+#[derive(Debug, Clone)]
+pub struct SocketOption {
+    pub protocol: ProtocolVersion,
+    pub ping_interval: Duration,
+    pub ping_timeout: Duration,
+    /// Transports the client may upgrade to
+    pub upgrades: Vec<String>,
+    /// See `ServerOptionBuilder::max_http_buffer_size`
+    pub max_payload: usize,
+    /// See `ServerOptionBuilder::send_buffer_size`
+    pub send_buffer_size: usize,
+    pub send_buffer_policy: BufferPolicy,
+}
+
 #[derive(Debug)]
 pub struct Socket<T: Transport> {
     sid: SID,
     transport: T,
-    ch: util::BiChan<Message, Message>,
-    ping_interval: u64,
-    ping_timeout: u64,
+    /// Commands from the `Server` and the application
+    rx: Receiver<Message>,
+    option: SocketOption,
+    /// When the next heartbeat action is due, see `run`
+    heartbeat: Instant,
+    /// A ping has been sent and its pong has not arrived yet (v4 only)
+    awaiting_pong: bool,
+    /// Messages from the application to the client, see `connection`
+    send_buffer: Arc<SendBuffer>,
+    /// Messages from the client to the application, see `connection`
+    messages: Option<Sender<PacketData>>,
+    state: watch::Sender<ReadyState>,
+    state_rx: watch::Receiver<ReadyState>,
+    closed: Option<oneshot::Sender<CloseReason>>,
 }
 
 impl<T: Transport> Socket<T> {
-    pub fn new(
-        transport: T,
-        ch: util::BiChan<Message, Message>,
-        ping_interval: u64,
-        ping_timeout: u64,
-    ) -> Self {
-        let sid = generate_sid();
+    pub fn new(sid: SID, transport: T, rx: Receiver<Message>, option: SocketOption) -> Self {
         debug!("sid: {:?}", sid);
-        Self {
+        let send_buffer = SendBuffer::new(option.send_buffer_size, option.send_buffer_policy);
+        let (state, state_rx) = watch::channel(ReadyState::Opening);
+        let mut ret = Self {
             transport,
             sid,
-            ch,
-            ping_interval,
-            ping_timeout,
-        }
+            rx,
+            option,
+            send_buffer: Arc::new(send_buffer),
+            heartbeat: Instant::now(),
+            awaiting_pong: false,
+            messages: None,
+            state,
+            state_rx,
+            closed: None,
+        };
+        ret.reset_heartbeat();
+        ret
     }
 
     pub fn sid(&self) -> SID {
         self.sid.clone()
     }
 
-    pub async fn handle_request(&mut self, message: &Message) {
+    pub fn ready_state(&self) -> ReadyState {
+        *self.state_rx.borrow()
+    }
+
+    fn set_ready_state(&mut self, state: ReadyState) {
+        trace!("socket {:?} is {:?}", self.sid, state);
+        let _ = self.state.broadcast(state);
+    }
+
+    /// Hand this socket to the application, `tx` being the sender of its `rx`
+    pub fn connection(&mut self, tx: Sender<Message>) -> Connection {
+        let (messages_tx, messages_rx) = mpsc::channel(MESSAGE_CAPACITY);
+        let (closed_tx, closed_rx) = oneshot::channel();
+        self.messages = Some(messages_tx);
+        self.closed = Some(closed_tx);
+        Connection::new(
+            self.sid(),
+            tx,
+            self.send_buffer.clone(),
+            self.state_rx.clone(),
+            messages_rx,
+            closed_rx,
+        )
+    }
+
+    /// Push the heartbeat deadline back after hearing from the client
+    ///
+    /// In v3 the client pings, so it must be heard from within `ping_interval + ping_timeout`.
+    /// In v4 the server pings, the next one is due after `ping_interval`.
+    fn reset_heartbeat(&mut self) {
+        let delay = match self.option.protocol {
+            ProtocolVersion::V3 => self.option.ping_interval + self.option.ping_timeout,
+            ProtocolVersion::V4 => self.option.ping_interval,
+        };
+        self.heartbeat = Instant::now() + delay;
+        self.awaiting_pong = false;
+    }
+
+    /// Send a ping and expect a pong within `ping_timeout`
+    async fn ping(&mut self) {
+        if let Err(e) = self.send(Packet::ping()).await {
+            error!("{:?}", e);
+        }
+        self.heartbeat = Instant::now() + self.option.ping_timeout;
+        self.awaiting_pong = true;
+    }
+
+    /// Close the socket, delivering what the application sent so far if `reason` allows it
+    async fn close(&mut self, reason: CloseReason) {
+        debug!("socket {:?} closed: {}", self.sid, reason);
+        self.set_ready_state(ReadyState::Closing);
+        // Refuse new messages before flushing, so that none is silently left behind
+        self.send_buffer.close();
+        if reason.is_graceful() {
+            self.flush().await;
+        }
+        if reason.expects_close_packet() {
+            if let Err(e) = self.transport.send_close().await {
+                error!("{:?}", e);
+            }
+        }
+        if let Err(e) = self.transport.close().await {
+            error!("{:?}", e);
+        }
+        self.messages = None;
+        self.set_ready_state(ReadyState::Closed);
+        if let Some(closed) = self.closed.take() {
+            let _ = closed.send(reason);
+        }
+    }
+
+    async fn send(&mut self, packet: Packet) -> Result {
+        self.transport
+            .send_packet(packet)
+            .await
+            .map_err(|e| format!("{:?}", e))
+    }
+
+    /// Hand the messages of the application over to the transport
+    async fn flush(&mut self) {
+        for packet in self.send_buffer.take_all() {
+            if let Err(e) = self.send(packet).await {
+                error!("{:?}", e);
+            }
+        }
+    }
+}
+
+impl<T: Transport + From<DynTransport>> Socket<T> {
+    /// Carry out a command, returns why the socket should stop if it should
+    pub async fn handle_request(&mut self, message: Message) -> Option<CloseReason> {
         debug!("incoming message {:?}", message);
-        let packets: Vec<Packet> = match message {
-            Message::Packet(p) => vec![p.clone()],
-            Message::Payload(p) => p.clone().into(),
-            Message::Close => unimplemented!(),
+        match message {
+            Message::Upgrade(transport) => self.upgrade(T::from(transport)).await,
+            Message::Flush => self.flush().await,
+            Message::Disconnect(reason) => return Some(reason),
+        }
+        None
+    }
+
+    /// Dispatch what the transport received, returns why the socket should stop if it should
+    pub async fn handle_packet(
+        &mut self,
+        packet: Option<std::result::Result<Packet, TransportError>>,
+    ) -> Option<CloseReason> {
+        let packet = match packet {
+            Some(Ok(packet)) => packet,
+            Some(Err(TransportError::MessageTooLarge)) => {
+                return Some(CloseReason::MessageTooLarge)
+            }
+            Some(Err(e)) => {
+                error!("{:?}", e);
+                return Some(CloseReason::TransportError);
+            }
+            None => return Some(CloseReason::TransportClose),
+        };
+        debug!("incoming packet {:?}", packet);
+        if packet.data.len() > self.option.max_payload {
+            return Some(CloseReason::MessageTooLarge);
+        }
+        // Anything from the client shows that a v3 client is alive
+        if self.option.protocol == ProtocolVersion::V3 {
+            self.reset_heartbeat();
+        }
+        let result = match packet.typ {
+            PacketType::Open | PacketType::Noop => Ok(()),
+            PacketType::Ping => self.on_ping(&packet).await,
+            PacketType::Pong => self.on_pong(&packet).await,
+            PacketType::Message => self.on_message(&packet).await,
+            PacketType::Upgrade => self.on_upgrade(&packet).await,
+            PacketType::Close => {
+                if let Err(e) = self.on_close(&packet).await {
+                    error!("{:?}", e);
+                }
+                return Some(CloseReason::ClientClose);
+            }
         };
-        for packet in packets.into_iter() {
-            // TODO
-            unimplemented!()
-            // match packet.typ {
-            //     PacketType::Open => {}
-            //     PacketType::Ping => self.on_ping(&packet).await,
-            //     PacketType::Pong => self.on_pong(&packet).await,
-            //     PacketType::Close => self.on_close(&packet).await,
-            //     PacketType::Message => self.on_message(&packet).await,
-            //     PacketType::Upgrade => self.on_upgrade(&packet).await,
-            //     PacketType::Noop => {}
-            // };
+        if let Err(e) = result {
+            error!("{:?}", e);
+        }
+        None
+    }
+
+    /// Switch to the transport the client upgraded to,
+    /// resending what the previous one has not delivered
+    async fn upgrade(&mut self, transport: T) {
+        debug!("socket {:?} upgraded", self.sid);
+        let mut previous = std::mem::replace(&mut self.transport, transport);
+        for packet in previous.take_pending() {
+            if let Err(e) = self.send(packet).await {
+                error!("{:?}", e);
+            }
         }
     }
 }
 
 #[async_trait]
-impl EngineIOSocket for Socket<websocket::WebSocket> {
+impl<T: Transport + From<DynTransport>> EngineIOSocket for Socket<T> {
     async fn on_open(&mut self) -> Result {
-        unimplemented!()
-        // let message = Packet::open(self.sid.clone()).encode();
-        // trace!("on open: {:?}", message);
-        // if let Err(e) = self.transport.send_message(message).await {
-        //     error!("{:?}", e);
-        // }
+        let welcome = WelcomeMessage {
+            sid: self.sid.clone(),
+            upgrades: self.option.upgrades.clone(),
+            ping_interval: self.option.ping_interval.as_millis() as u64,
+            ping_timeout: self.option.ping_timeout.as_millis() as u64,
+            max_payload: match self.option.protocol {
+                ProtocolVersion::V3 => None,
+                ProtocolVersion::V4 => Some(self.option.max_payload),
+            },
+        };
+        let packet = Packet::open(&welcome);
+        trace!("on open: {:?}", packet);
+        self.send(packet).await?;
+        self.set_ready_state(ReadyState::Open);
+        Ok(())
     }
 
     async fn on_message(&mut self, packet: &Packet) -> Result {
-        unimplemented!()
-        // trace!("on message: {:?}", packet);
-        // let message = packet.encode();
-        // self.transport.send_message(message).await.unwrap();
+        trace!("on message: {:?}", packet);
+        if let Some(messages) = &mut self.messages {
+            // Waits while the application is behind, nothing is read from the transport meanwhile
+            let start = Instant::now();
+            // The application may have dropped its connection, which is not an error
+            let _ = messages.send(packet.data.clone()).await;
+            // The client could not be heard from while waiting, which must not time it out
+            self.heartbeat += start.elapsed();
+        }
+        Ok(())
     }
 
     async fn on_close(&mut self, _packet: &Packet) -> Result {
-        unimplemented!()
+        trace!("on close: {:?}", self.sid);
+        Ok(())
     }
 
     async fn on_ping(&mut self, packet: &Packet) -> Result {
-        unimplemented!()
-        // trace!("on ping: {:?}", packet);
-        // let message = Packet::pong().encode();
-        // if let Err(e) = self.transport.send_message(message).await {
-        //     error!("{:?}", e);
-        // }
+        trace!("on ping: {:?}", packet);
+        match self.option.protocol {
+            ProtocolVersion::V3 => self.send(Packet::pong()).await,
+            ProtocolVersion::V4 => Ok(()),
+        }
     }
 
-    async fn on_pong(&mut self, _packet: &Packet) -> Result {
-        unimplemented!()
+    async fn on_pong(&mut self, packet: &Packet) -> Result {
+        trace!("on pong: {:?}", packet);
+        if self.option.protocol == ProtocolVersion::V4 {
+            self.reset_heartbeat();
+        }
+        Ok(())
     }
 
-    async fn on_upgrade(&mut self, _packet: &Packet) -> Result {
-        unimplemented!()
+    async fn on_upgrade(&mut self, packet: &Packet) -> Result {
+        // The upgrade is completed by `Message::Upgrade`, the packet itself is handled by `Server`
+        trace!("on upgrade: {:?}", packet);
+        Ok(())
     }
 
     async fn run(mut self) {
-        unimplemented!()
-        // let ping_tick = tick(Duration::from_millis(self.ping_interval));
-        // let (tx, rx) = unbounded();
-        // let reset = move || {
-        //     tx.send(());
-        // };
-        // let ping_timeout = util::resettable_timeout(Duration::from_millis(self.ping_timeout), rx);
-        //
-        // loop {
-        //     select! {
-        //         recv(self.ch.rx) -> msg => {
-        //             if let Ok(s) = msg {
-        //                 self.handle_request(&s);
-        //             }
-        //         },
-        //         recv(ping_tick) -> _ => {
-        //             if let Err(err) = self.transport.send_ping().await {
-        //                 error!("{:?}", err);
-        //             }
-        //         },
-        //         recv(ping_timeout) -> _ => {
-        //             // TODO Close connection
-        //             unimplemented!()
-        //         },
-        //     }
-        // }
+        let reason = loop {
+            tokio::select! {
+                message = self.rx.recv() => match message {
+                    Some(message) => {
+                        if let Some(reason) = self.handle_request(message).await {
+                            break reason;
+                        }
+                    }
+                    None => break CloseReason::TransportClose,
+                },
+                _ = self.send_buffer.pushed(), if self.transport.writable() => self.flush().await,
+                packet = self.transport.recv() => {
+                    if let Some(reason) = self.handle_packet(packet).await {
+                        break reason;
+                    }
+                },
+                _ = time::delay_until(self.heartbeat) => {
+                    if self.option.protocol == ProtocolVersion::V3 || self.awaiting_pong {
+                        break CloseReason::PingTimeout;
+                    }
+                    self.ping().await;
+                }
+            }
+        };
+        self.close(reason).await;
     }
 }
 
-#[async_trait]
-impl EngineIOSocket for Socket<polling::Polling> {
-    async fn on_open(&mut self) -> Result {
-        unimplemented!()
-        // let message = Packet::open(self.sid.clone()).encode();
-        // trace!("on open: {:?}", message);
-        // if let Err(e) = self.transport.send_message(message).await {
-        //     error!("{:?}", e);
-        // }
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::connection::SendError;
+    use crate::transports::polling::{Buffer, Polling};
+
+    use futures::StreamExt;
+
+    fn option(protocol: ProtocolVersion, ping_interval: u64, ping_timeout: u64) -> SocketOption {
+        SocketOption {
+            protocol,
+            ping_interval: Duration::from_millis(ping_interval),
+            ping_timeout: Duration::from_millis(ping_timeout),
+            upgrades: Vec::new(),
+            max_payload: 1000,
+            send_buffer_size: 16,
+            send_buffer_policy: BufferPolicy::Wait,
+        }
     }
 
-    async fn on_message(&mut self, packet: &Packet) -> Result {
-        unimplemented!()
-        // trace!("on message: {:?}", packet);
-        // let message = packet.encode();
-        // self.transport.send_message(message).await.unwrap();
+    /// A socket on a polling transport, along with what the client polls, what it posts
+    /// and the commands of the socket
+    fn polling_socket(
+        option: SocketOption,
+    ) -> (
+        Socket<DynTransport>,
+        Arc<Buffer>,
+        Sender<Packet>,
+        Sender<Message>,
+    ) {
+        let (tx, rx) = mpsc::channel(COMMAND_CAPACITY);
+        let transport = Polling::new();
+        let buffer = transport.buffer();
+        let incoming = transport.incoming();
+        let socket =
+            Socket::<DynTransport>::new("sid".to_string(), Box::new(transport), rx, option);
+        (socket, buffer, incoming, tx)
     }
 
-    async fn on_close(&mut self, _packet: &Packet) -> Result {
-        unimplemented!()
+    async fn next_packet(buffer: &Buffer) -> PacketType {
+        let packets = time::timeout(
+            Duration::from_millis(500),
+            buffer.drain(1, ProtocolVersion::V4),
+        )
+        .await
+        .expect("no packet was sent");
+        packets[0].typ
     }
 
-    async fn on_ping(&mut self, packet: &Packet) -> Result {
-        unimplemented!()
-        // trace!("on ping: {:?}", packet);
-        // let message = Packet::pong().encode();
-        // if let Err(e) = self.transport.send_message(message).await {
-        //     error!("{:?}", e);
-        // }
-    }
-
-    async fn on_pong(&mut self, _packet: &Packet) -> Result {
-        unimplemented!()
-    }
-
-    async fn on_upgrade(&mut self, _packet: &Packet) -> Result {
-        unimplemented!()
-    }
-
-    async fn run(self) {
-        unimplemented!()
-        // // TODO Call handshake if sid is not set
-        // while let Some(Ok(result)) = self.rx.next().await {
-        //     debug!("incoming message {:?}", result);
-        //     if result.is_close() {
-        //         // Close socket
-        //         unimplemented!()
-        //     }
-        //     let packet = Packet::decode(result.to_str().unwrap());
-        //     match packet.typ {
-        //         PacketType::Open => {}
-        //         PacketType::Ping => self.on_ping(&packet).await,
-        //         PacketType::Pong => self.on_pong(&packet).await,
-        //         PacketType::Close => self.on_close(&packet).await,
-        //         PacketType::Message => self.on_message(&packet).await,
-        //         PacketType::Upgrade => self.on_upgrade(&packet).await,
-        //         PacketType::Noop => {}
-        //     };
-        // }
+    #[test]
+    fn base64id_sids() {
+        let sids: std::collections::HashSet<SID> = (0..1000).map(|_| Base64Id.generate()).collect();
+        assert_eq!(sids.len(), 1000);
+        for sid in sids {
+            assert_eq!(sid.len(), 20);
+            assert!(sid
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'));
+        }
+    }
+
+    #[tokio::test]
+    async fn v4_pings_and_times_out() {
+        let (socket, buffer, _incoming, _tx) = polling_socket(option(ProtocolVersion::V4, 20, 20));
+        tokio::spawn(socket.run());
+
+        assert_eq!(next_packet(&buffer).await, PacketType::Ping);
+        assert_eq!(next_packet(&buffer).await, PacketType::Close);
+    }
+
+    #[tokio::test]
+    async fn v4_pong_keeps_socket_alive() {
+        let (socket, buffer, mut incoming, _tx) =
+            polling_socket(option(ProtocolVersion::V4, 20, 50));
+        tokio::spawn(socket.run());
+
+        for _ in 0..3 {
+            assert_eq!(next_packet(&buffer).await, PacketType::Ping);
+            incoming.send(Packet::pong()).await.unwrap();
+        }
+        assert_eq!(next_packet(&buffer).await, PacketType::Ping);
+    }
+
+    #[tokio::test]
+    async fn v3_answers_pings_and_times_out() {
+        let (socket, buffer, mut incoming, _tx) =
+            polling_socket(option(ProtocolVersion::V3, 20, 20));
+        tokio::spawn(socket.run());
+
+        incoming.send(Packet::ping()).await.unwrap();
+        assert_eq!(next_packet(&buffer).await, PacketType::Pong);
+        assert_eq!(next_packet(&buffer).await, PacketType::Close);
+    }
+
+    #[tokio::test]
+    async fn upgrade_resends_pending_packets() {
+        let (mut socket, _buffer, _incoming, mut tx) =
+            polling_socket(option(ProtocolVersion::V3, 1000, 1000));
+        let connection = socket.connection(tx.clone());
+        // Nobody polls the open packet nor the message
+        socket.on_open().await.unwrap();
+        tokio::spawn(socket.run());
+        connection.send("pending").await.unwrap();
+
+        let upgraded = Polling::new();
+        let buffer = upgraded.buffer();
+        tx.send(Message::Upgrade(Box::new(upgraded))).await.unwrap();
+        assert_eq!(next_packet(&buffer).await, PacketType::Open);
+        assert_eq!(next_packet(&buffer).await, PacketType::Message);
+    }
+
+    #[tokio::test]
+    async fn slow_application_holds_back_transport() {
+        let (mut socket, _buffer, mut incoming, tx) =
+            polling_socket(option(ProtocolVersion::V4, 1000, 1000));
+        let mut connection = socket.connection(tx);
+        tokio::spawn(socket.run());
+
+        // The messages channel fills up, then the transport is not read until its queue is full
+        let mut sent = 0;
+        while time::timeout(
+            Duration::from_millis(20),
+            incoming.send(Packet::message("a")),
+        )
+        .await
+        .is_ok()
+        {
+            sent += 1;
+            assert!(sent < 4 * MESSAGE_CAPACITY, "nothing holds the client back");
+        }
+        assert!(sent > MESSAGE_CAPACITY);
+
+        assert_eq!(connection.next().await, Some(PacketData::Text("a".into())));
+        time::timeout(
+            Duration::from_millis(100),
+            incoming.send(Packet::message("b")),
+        )
+        .await
+        .expect("reading did not resume")
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn connection_exchanges_messages() {
+        let (mut socket, buffer, mut incoming, mut tx) =
+            polling_socket(option(ProtocolVersion::V4, 1000, 1000));
+        let mut connection = socket.connection(tx.clone());
+        let closed = connection.closed();
+        tokio::spawn(socket.run());
+
+        incoming.send(Packet::message("hello")).await.unwrap();
+        assert_eq!(
+            connection.next().await,
+            Some(PacketData::Text("hello".to_string()))
+        );
+        connection.send("world").await.unwrap();
+        tx.send(Message::Flush).await.unwrap();
+        assert_eq!(next_packet(&buffer).await, PacketType::Message);
+        connection.drained().await;
+
+        connection.close().await;
+        assert_eq!(closed.await, CloseReason::ForcedClose);
+        assert_eq!(next_packet(&buffer).await, PacketType::Close);
+        assert_eq!(connection.next().await, None);
+    }
+
+    #[tokio::test]
+    async fn close_flushes_pending_messages() {
+        let (mut socket, buffer, _incoming, tx) =
+            polling_socket(option(ProtocolVersion::V4, 1000, 1000));
+        let connection = socket.connection(tx);
+        let closed = connection.closed();
+        assert_eq!(connection.ready_state(), ReadyState::Opening);
+        socket.on_open().await.unwrap();
+        assert_eq!(connection.ready_state(), ReadyState::Open);
+        tokio::spawn(socket.run());
+
+        // Nobody polls, so the message is still buffered when the socket closes
+        assert_eq!(next_packet(&buffer).await, PacketType::Open);
+        connection.send("a").await.unwrap();
+        connection.close().await;
+        assert_eq!(closed.await, CloseReason::ForcedClose);
+        assert_eq!(connection.ready_state(), ReadyState::Closed);
+        assert_eq!(next_packet(&buffer).await, PacketType::Message);
+        assert_eq!(next_packet(&buffer).await, PacketType::Close);
+        assert_eq!(connection.send("b").await, Err(SendError::Closed));
+    }
+
+    #[tokio::test]
+    async fn oversized_packet_closes_socket() {
+        let (mut socket, buffer, mut incoming, tx) =
+            polling_socket(option(ProtocolVersion::V4, 1000, 1000));
+        let mut connection = socket.connection(tx);
+        let closed = connection.closed();
+        tokio::spawn(socket.run());
+
+        incoming.send(Packet::binary(&[0; 1001])).await.unwrap();
+        assert_eq!(closed.await, CloseReason::MessageTooLarge);
+        assert_eq!(next_packet(&buffer).await, PacketType::Close);
+        assert_eq!(connection.next().await, None);
+    }
+
+    #[tokio::test]
+    async fn full_send_buffer_disconnects() {
+        let mut option = option(ProtocolVersion::V4, 1000, 1000);
+        option.send_buffer_size = 1;
+        option.send_buffer_policy = BufferPolicy::Disconnect;
+        let (mut socket, buffer, _incoming, tx) = polling_socket(option);
+        let connection = socket.connection(tx);
+        let closed = connection.closed();
+        tokio::spawn(socket.run());
+
+        // Nobody polls, so the first message stays in the send buffer
+        connection.send("a").await.unwrap();
+        assert_eq!(connection.send("b").await, Err(SendError::Full));
+        assert_eq!(closed.await, CloseReason::SendBufferFull);
+        assert_eq!(next_packet(&buffer).await, PacketType::Close);
     }
 }
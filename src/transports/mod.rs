@@ -2,19 +2,94 @@ pub mod polling;
 pub mod polling_jsonp;
 pub mod websocket;
 
+use crate::packet::{DecodeError, Packet};
+
 use async_trait::async_trait;
+use tokio_tungstenite::tungstenite;
 
 #[derive(Debug)]
 pub enum TransportError {
-    WebSocketError(warp::Error),
+    /// Error of the WebSocket connection, from whichever framework accepted it
+    WebSocketError(Box<dyn std::error::Error + Send + Sync>),
+    /// A message exceeded `max_http_buffer_size`
+    MessageTooLarge,
+    /// The client sent something that is not a packet
+    DecodeError(DecodeError),
+}
+
+impl From<tungstenite::Error> for TransportError {
+    fn from(e: tungstenite::Error) -> Self {
+        match e {
+            tungstenite::Error::Capacity(_) => Self::MessageTooLarge,
+            e => Self::WebSocketError(Box::new(e)),
+        }
+    }
 }
 
 pub type Result = std::result::Result<(), TransportError>;
 
+/// Names of the transports clients may request
+pub const NAMES: &[&str] = &[polling::NAME, websocket::NAME];
+
+/// Names of the transports a session on `transport` can be upgraded to
+pub fn upgrades(transport: &str) -> &'static [&'static str] {
+    match transport {
+        polling::NAME => &[websocket::NAME],
+        _ => &[],
+    }
+}
+
+/// Transport of a socket that can be replaced by an upgrade
+pub type DynTransport = Box<dyn Transport>;
+
+/// Connection to a client, carrying packets both ways
+///
+/// A `Socket` sends packets through the transport and reads what the client sends with `recv`,
+/// so any implementation can serve a session.
+#[async_trait]
+pub trait Transport: Send {
+    async fn send_packet(&mut self, packet: Packet) -> Result;
+    async fn send_close(&mut self) -> Result;
+
+    /// Next packet from the client, `None` once the client is gone
+    ///
+    /// Packets are not lost if the returned future is dropped before it completes,
+    /// so that it can be raced against other events.
+    async fn recv(&mut self) -> Option<std::result::Result<Packet, TransportError>>;
+
+    /// Whether a packet sent now would reach the client right away instead of being queued
+    fn writable(&self) -> bool {
+        true
+    }
+
+    /// Close the connection, after the close packet has been sent
+    async fn close(&mut self) -> Result;
+
+    /// Take packets that were queued but not delivered yet,
+    /// so that they can be resent on the transport replacing this one
+    fn take_pending(&mut self) -> Vec<Packet> {
+        Vec::new()
+    }
+}
+
 #[async_trait]
-pub trait Transport {
-    async fn send_message(&self, message: String) -> Result;
-    async fn send_ping(&self) -> Result;
-    async fn send_pong(&self) -> Result;
-    async fn send_close(&self) -> Result;
+impl<T: Transport + ?Sized> Transport for Box<T> {
+    async fn send_packet(&mut self, packet: Packet) -> Result {
+        (**self).send_packet(packet).await
+    }
+    async fn send_close(&mut self) -> Result {
+        (**self).send_close().await
+    }
+    async fn recv(&mut self) -> Option<std::result::Result<Packet, TransportError>> {
+        (**self).recv().await
+    }
+    fn writable(&self) -> bool {
+        (**self).writable()
+    }
+    async fn close(&mut self) -> Result {
+        (**self).close().await
+    }
+    fn take_pending(&mut self) -> Vec<Packet> {
+        (**self).take_pending()
+    }
 }
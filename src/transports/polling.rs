@@ -1,27 +1,208 @@
-use crate::transports::{Result, Transport};
+use std::collections::VecDeque;
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::packet::{Packet, ProtocolVersion};
+use crate::transports::{Result, Transport, TransportError};
 
 use async_trait::async_trait;
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::sync::Notify;
+
+pub const NAME: &str = "polling";
+
+/// How many posted packets may wait for the socket before polling requests are held back
+const INCOMING_CAPACITY: usize = 64;
+
+/// Outgoing packets of a polling session, flushed by polling requests
+#[derive(Debug, Default)]
+pub struct Buffer {
+    packets: Mutex<VecDeque<Packet>>,
+    notify: Notify,
+    /// Notified when the buffer has been emptied
+    flushed: Notify,
+    /// Polling requests waiting in `drain`
+    waiting: AtomicUsize,
+}
+
+/// Counts a polling request as waiting until it is dropped
+struct Waiting<'a>(&'a AtomicUsize);
+
+impl<'a> Waiting<'a> {
+    fn new(waiting: &'a AtomicUsize) -> Self {
+        waiting.fetch_add(1, Ordering::SeqCst);
+        Self(waiting)
+    }
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Buffer {
+    pub fn push(&self, packet: Packet) {
+        self.packets.lock().unwrap().push_back(packet);
+        self.notify.notify();
+    }
+
+    pub fn take_all(&self) -> Vec<Packet> {
+        let packets = self.packets.lock().unwrap().drain(..).collect();
+        self.flushed.notify();
+        packets
+    }
+
+    /// Wait until every packet has been taken
+    pub async fn flushed(&self) {
+        loop {
+            if self.packets.lock().unwrap().is_empty() {
+                return;
+            }
+            self.flushed.notified().await;
+        }
+    }
+
+    /// Wait for packets and take as many as fit in `max_bytes` once encoded
+    ///
+    /// The first packet is always taken so that an oversized packet can not stall the session.
+    ///
+    /// The request counts as waiting as soon as this is called, see `Polling::writable`.
+    pub fn drain(
+        &self,
+        max_bytes: usize,
+        protocol: ProtocolVersion,
+    ) -> impl Future<Output = Vec<Packet>> + '_ {
+        let waiting = Waiting::new(&self.waiting);
+        async move {
+            let _waiting = waiting;
+            self.take(max_bytes, protocol).await
+        }
+    }
+
+    async fn take(&self, max_bytes: usize, protocol: ProtocolVersion) -> Vec<Packet> {
+        loop {
+            {
+                let mut packets = self.packets.lock().unwrap();
+                if !packets.is_empty() {
+                    let mut size = 0;
+                    let count = packets
+                        .iter()
+                        .take_while(|p| {
+                            let empty = size == 0;
+                            size += p.encode(protocol).len();
+                            empty || size <= max_bytes
+                        })
+                        .count();
+                    let taken = packets.drain(..count).collect();
+                    if packets.is_empty() {
+                        self.flushed.notify();
+                    }
+                    return taken;
+                }
+            }
+            self.notify.notified().await;
+        }
+    }
+}
+
+pub struct Polling {
+    buffer: Arc<Buffer>,
+    /// Packets posted by the client, see `incoming`
+    tx: Sender<Packet>,
+    rx: Receiver<Packet>,
+}
 
-pub struct Polling {}
+impl Default for Polling {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 impl Polling {
     pub fn new() -> Self {
-        Self {}
+        let (tx, rx) = mpsc::channel(INCOMING_CAPACITY);
+        Self {
+            buffer: Arc::new(Buffer::default()),
+            tx,
+            rx,
+        }
+    }
+
+    pub fn buffer(&self) -> Arc<Buffer> {
+        self.buffer.clone()
+    }
+
+    /// Where the packets of polling requests go, `recv` yields them
+    pub fn incoming(&self) -> Sender<Packet> {
+        self.tx.clone()
     }
 }
 
 #[async_trait]
 impl Transport for Polling {
-    async fn send_message(&self, message: String) -> Result {
+    async fn send_packet(&mut self, packet: Packet) -> Result {
+        self.buffer.push(packet);
         Ok(())
     }
-    async fn send_ping(&self) -> Result {
-        Ok(())
+    async fn send_close(&mut self) -> Result {
+        self.send_packet(Packet::close()).await
     }
-    async fn send_pong(&self) -> Result {
-        Ok(())
+    async fn recv(&mut self) -> Option<std::result::Result<Packet, TransportError>> {
+        self.rx.recv().await.map(Ok)
     }
-    async fn send_close(&self) -> Result {
+    fn writable(&self) -> bool {
+        self.buffer.waiting.load(Ordering::SeqCst) > 0
+    }
+    async fn close(&mut self) -> Result {
+        // Requests end by themselves, the close packet answers the one in flight
         Ok(())
     }
+    fn take_pending(&mut self) -> Vec<Packet> {
+        self.buffer.take_all()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn drain_respects_byte_limit() {
+        let buffer = Buffer::default();
+        for m in ["aaaa", "bbbb", "cccc"].iter() {
+            buffer.push(Packet::message(m));
+        }
+        // Each packet encodes to 5 bytes
+        let packets = buffer.drain(10, ProtocolVersion::V4).await;
+        assert_eq!(packets.len(), 2);
+        let packets = buffer.drain(10, ProtocolVersion::V4).await;
+        assert_eq!(packets.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn drain_takes_oversized_packet() {
+        let buffer = Buffer::default();
+        buffer.push(Packet::message("too large"));
+        buffer.push(Packet::ping());
+        let packets = buffer.drain(1, ProtocolVersion::V4).await;
+        assert_eq!(packets.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn writable_while_polled() {
+        let mut polling = Polling::new();
+        assert!(!polling.writable());
+        let buffer = polling.buffer();
+        let poll = tokio::spawn(async move { buffer.drain(100, ProtocolVersion::V4).await });
+        tokio::time::delay_for(std::time::Duration::from_millis(10)).await;
+        assert!(polling.writable());
+        polling.send_packet(Packet::ping()).await.unwrap();
+        assert_eq!(poll.await.unwrap().len(), 1);
+        assert!(!polling.writable());
+
+        polling.incoming().send(Packet::pong()).await.unwrap();
+        assert!(polling.recv().await.unwrap().is_ok());
+    }
 }
@@ -0,0 +1,94 @@
+//! JSONP flavour of the polling transport, for browsers without cross-origin XHR
+//!
+//! Payloads are served as `___eio[<j>]("<payload>");` scripts and
+//! sent back as form-encoded `d=<payload>` bodies.
+
+use crate::packet::DecodeError;
+
+use std::collections::HashMap;
+
+/// Callback of a JSONP client, given by the `j` query parameter
+#[derive(Debug, Clone, PartialEq)]
+pub struct Jsonp {
+    index: String,
+}
+
+impl Jsonp {
+    /// `None` unless `j` is a callback index, anything else could inject script
+    pub fn new(j: &str) -> Option<Self> {
+        if j.is_empty() || !j.chars().all(|c| c.is_ascii_digit()) {
+            return None;
+        }
+        Some(Self {
+            index: j.to_string(),
+        })
+    }
+
+    /// Wrap an encoded payload into a call to the callback
+    pub fn wrap(&self, payload: &str) -> String {
+        // U+2028 and U+2029 are valid in JSON strings but end lines in older JS engines
+        let payload = serde_json::to_string(payload)
+            .unwrap()
+            .replace('\u{2028}', "\\u2028")
+            .replace('\u{2029}', "\\u2029");
+        format!("___eio[{}]({});", self.index, payload)
+    }
+}
+
+/// Extract the payload from a `d=<payload>` form body
+pub fn decode_body(body: &[u8]) -> Result<String, DecodeError> {
+    let mut form: HashMap<String, String> =
+        serde_urlencoded::from_bytes(body).map_err(|_| DecodeError::InvalidPayload)?;
+    form.remove("d")
+        .map(|d| unescape_newlines(&d))
+        .ok_or(DecodeError::EmptyPayload)
+}
+
+/// Clients send newlines as `\n` and a literal `\n` as `\\n`
+fn unescape_newlines(data: &str) -> String {
+    let mut unescaped = String::with_capacity(data.len());
+    let mut rest = data;
+    while !rest.is_empty() {
+        if let Some(r) = rest.strip_prefix("\\\\n") {
+            unescaped.push_str("\\n");
+            rest = r;
+        } else if let Some(r) = rest.strip_prefix("\\n") {
+            unescaped.push('\n');
+            rest = r;
+        } else {
+            let c = rest.chars().next().unwrap();
+            unescaped.push(c);
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+    unescaped
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn wrap_escapes_payload() {
+        let jsonp = Jsonp::new("3").unwrap();
+        assert_eq!(
+            jsonp.wrap("4\"a\\b\u{2028}"),
+            r#"___eio[3]("4\"a\\b\u2028");"#
+        );
+    }
+
+    #[test]
+    fn valid_index() {
+        assert!(Jsonp::new("12").is_some());
+        assert!(Jsonp::new("").is_none());
+        assert!(Jsonp::new("a").is_none());
+        assert!(Jsonp::new("3);alert(1").is_none());
+    }
+
+    #[test]
+    fn decode_form_body() {
+        assert_eq!(decode_body(b"d=4hello%20world").unwrap(), "4hello world");
+        assert_eq!(decode_body(br"d=4a\nb\\nc").unwrap(), "4a\nb\\nc");
+        assert!(decode_body(b"x=4hello").is_err());
+    }
+}
@@ -1,32 +1,132 @@
-use crate::transports::{Result, Transport};
+use crate::packet::{DecodeError, Packet, ProtocolVersion};
+use crate::transports::{Result, Transport, TransportError};
+
+use std::pin::Pin;
 
 use async_trait::async_trait;
-use futures::stream::{SplitSink, SplitStream};
-use futures::StreamExt;
-use warp::filters::ws::{Message, WebSocket as WS};
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use log::{debug, trace};
+
+pub use tokio_tungstenite::tungstenite::Message as Frame;
+
+pub const NAME: &str = "websocket";
+
+/// Sending half of a WebSocket connection
+pub type FrameSink = Pin<Box<dyn Sink<Frame, Error = TransportError> + Send>>;
+
+/// Receiving half of a WebSocket connection
+pub type FrameStream =
+    Pin<Box<dyn Stream<Item = std::result::Result<Frame, TransportError>> + Send>>;
 
+/// Transport over a WebSocket, one packet per frame
+///
+/// Pings are answered by tungstenite itself, a close frame from the client ends `recv`.
 pub struct WebSocket {
-    tx: SplitSink<WS, Message>,
+    tx: FrameSink,
+    rx: FrameStream,
+    protocol: ProtocolVersion,
 }
 
 impl WebSocket {
-    pub fn new(tx: SplitSink<WS, Message>) -> Self {
-        Self { tx }
+    pub fn new(tx: FrameSink, rx: FrameStream, protocol: ProtocolVersion) -> Self {
+        Self { tx, rx, protocol }
+    }
+}
+
+/// Decode a data frame into a packet, `None` for control frames
+pub fn decode_message(
+    message: &Frame,
+    protocol: ProtocolVersion,
+) -> Option<std::result::Result<Packet, DecodeError>> {
+    match message {
+        Frame::Text(s) => Some(Packet::decode(s, protocol)),
+        Frame::Binary(b) => Some(Packet::decode_binary(b, protocol)),
+        _ => None,
     }
 }
 
 #[async_trait]
 impl Transport for WebSocket {
-    async fn send_message(&self, message: String) -> Result {
-        Ok(())
+    async fn send_packet(&mut self, packet: Packet) -> Result {
+        // Binary data goes out as a binary frame, everything else as text
+        let message = if packet.is_binary() {
+            Frame::Binary(packet.encode_binary(self.protocol))
+        } else {
+            Frame::Text(packet.encode(self.protocol))
+        };
+        self.tx.send(message).await
     }
-    async fn send_ping(&self) -> Result {
-        Ok(())
+    async fn send_close(&mut self) -> Result {
+        self.send_packet(Packet::close()).await
     }
-    async fn send_pong(&self) -> Result {
-        Ok(())
+    async fn recv(&mut self) -> Option<std::result::Result<Packet, TransportError>> {
+        while let Some(frame) = self.rx.next().await {
+            let frame = match frame {
+                Ok(frame) => frame,
+                Err(e) => return Some(Err(e)),
+            };
+            if let Frame::Close(close) = &frame {
+                debug!("close frame {:?}", close);
+                return None;
+            }
+            match decode_message(&frame, self.protocol) {
+                Some(packet) => return Some(packet.map_err(TransportError::DecodeError)),
+                None => trace!("control frame {:?}", frame),
+            }
+        }
+        None
     }
-    async fn send_close(&self) -> Result {
-        Ok(())
+    async fn close(&mut self) -> Result {
+        self.tx.close().await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::packet::{PacketData, PacketType};
+
+    use futures::channel::mpsc;
+
+    fn websocket(frames: Vec<Frame>) -> (WebSocket, mpsc::UnboundedReceiver<Frame>) {
+        let (tx, sent) = mpsc::unbounded();
+        let tx = tx.sink_map_err(|e| TransportError::WebSocketError(Box::new(e)));
+        let rx = futures::stream::iter(frames.into_iter().map(Ok));
+        let ws = WebSocket::new(Box::pin(tx), Box::pin(rx), ProtocolVersion::V4);
+        (ws, sent)
+    }
+
+    #[tokio::test]
+    async fn recv_skips_control_frames() {
+        let (mut ws, _) = websocket(vec![
+            Frame::Ping(vec![1]),
+            Frame::Text("4hello".to_string()),
+            Frame::Close(None),
+            Frame::Text("4lost".to_string()),
+        ]);
+        let packet = ws.recv().await.unwrap().unwrap();
+        assert_eq!(packet.typ, PacketType::Message);
+        assert_eq!(packet.data, PacketData::Text("hello".to_string()));
+        assert!(ws.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn recv_reports_malformed_frames() {
+        let (mut ws, _) = websocket(vec![Frame::Text("9".to_string())]);
+        assert!(matches!(
+            ws.recv().await,
+            Some(Err(TransportError::DecodeError(_)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn send_encodes_frames() {
+        let (mut ws, mut sent) = websocket(vec![]);
+        ws.send_packet(Packet::message("hi")).await.unwrap();
+        ws.send_packet(Packet::binary(&[1, 2])).await.unwrap();
+        ws.close().await.unwrap();
+        assert_eq!(sent.next().await, Some(Frame::Text("4hi".to_string())));
+        assert_eq!(sent.next().await, Some(Frame::Binary(vec![1, 2])));
+        assert_eq!(sent.next().await, None);
     }
 }